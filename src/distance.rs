@@ -1,6 +1,7 @@
+use serde::{Deserialize, Serialize};
 use std::f32::consts;
 
-#[derive(Clone, Debug, PartialEq, Default)]
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
 pub struct EarthLocation {
     pub latitude: f32,
     pub longitude: f32,
@@ -1,10 +1,13 @@
 #![allow(dead_code)]
 
+use std::fmt;
+
 #[derive(Debug)]
 pub enum SpeedTestError {
     Reqwest(reqwest::Error),
     Io(::std::io::Error),
     Csv(csv::Error),
+    Json(serde_json::Error),
     ParseFloatError(std::num::ParseFloatError),
     ParseIntError(std::num::ParseIntError),
     AddrParseError(std::net::AddrParseError),
@@ -14,12 +17,48 @@ pub enum SpeedTestError {
     LatencyTestInvalidPath,
     LatencyTestNoServerError,
     LatencyTestClosestError,
+    /// A server ID asked for is not in the server list.
+    ServerNotFound(u32),
+    /// Every upload request failed, with the error of the first one.
+    UploadFailed(String),
     UrlParseError(url::ParseError),
     SystemTimeError(std::time::SystemTimeError),
     ParseShareUrlError,
     ThreadPoolBuildError(rayon::ThreadPoolBuildError),
+    Cancelled,
+}
+
+impl fmt::Display for SpeedTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpeedTestError::Reqwest(err) => write!(f, "{err}"),
+            SpeedTestError::Io(err) => write!(f, "{err}"),
+            SpeedTestError::Csv(err) => write!(f, "{err}"),
+            SpeedTestError::Json(err) => write!(f, "{err}"),
+            SpeedTestError::ParseFloatError(err) => write!(f, "{err}"),
+            SpeedTestError::ParseIntError(err) => write!(f, "{err}"),
+            SpeedTestError::AddrParseError(err) => write!(f, "{err}"),
+            SpeedTestError::RoXmlTreeError(err) => write!(f, "{err}"),
+            SpeedTestError::ConfigParseError => write!(f, "Could not parse the configuration"),
+            SpeedTestError::ServerParseError => write!(f, "Could not parse the server list"),
+            SpeedTestError::LatencyTestInvalidPath => write!(f, "Invalid server URL"),
+            SpeedTestError::LatencyTestNoServerError => write!(f, "No servers to test"),
+            SpeedTestError::LatencyTestClosestError => {
+                write!(f, "No server could be reached")
+            }
+            SpeedTestError::ServerNotFound(id) => write!(f, "Server {id} is not in the list"),
+            SpeedTestError::UploadFailed(err) => write!(f, "Every upload request failed: {err}"),
+            SpeedTestError::UrlParseError(err) => write!(f, "{err}"),
+            SpeedTestError::SystemTimeError(err) => write!(f, "{err}"),
+            SpeedTestError::ParseShareUrlError => write!(f, "Could not parse the share URL"),
+            SpeedTestError::ThreadPoolBuildError(err) => write!(f, "{err}"),
+            SpeedTestError::Cancelled => write!(f, "Cancelled"),
+        }
+    }
 }
 
+impl std::error::Error for SpeedTestError {}
+
 impl From<reqwest::Error> for SpeedTestError {
     fn from(err: reqwest::Error) -> SpeedTestError {
         SpeedTestError::Reqwest(err)
@@ -38,6 +77,12 @@ impl From<csv::Error> for SpeedTestError {
     }
 }
 
+impl From<serde_json::Error> for SpeedTestError {
+    fn from(err: serde_json::Error) -> SpeedTestError {
+        SpeedTestError::Json(err)
+    }
+}
+
 impl From<std::num::ParseFloatError> for SpeedTestError {
     fn from(err: std::num::ParseFloatError) -> SpeedTestError {
         SpeedTestError::ParseFloatError(err)
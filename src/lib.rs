@@ -3,9 +3,18 @@
 pub mod distance;
 pub mod error;
 pub mod speedtest;
+#[cfg(feature = "async")]
+pub mod speedtest_async;
+pub mod speedtest_cache;
 pub mod speedtest_config;
 pub mod speedtest_csv;
+pub mod speedtest_json;
+pub mod speedtest_loaded_latency;
 pub mod speedtest_servers_config;
+pub mod speedtest_session;
+pub mod statistics;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 #[cfg(not(feature = "log"))]
 mod log;
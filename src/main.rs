@@ -1,22 +1,25 @@
-mod distance;
-mod error;
 #[cfg(not(feature = "log"))]
 mod log;
-mod speedtest;
-mod speedtest_config;
-mod speedtest_csv;
-mod speedtest_servers_config;
 
-use crate::speedtest_csv::SpeedTestCsvResult;
-use chrono::Utc;
 use clap::Parser;
 #[cfg(feature = "log")]
 use log::info;
 #[cfg(not(feature = "log"))]
 use log::info;
+use speedtest_rs::speedtest_csv::SpeedTestCsvResult;
+use speedtest_rs::speedtest_json::SpeedTestJsonResult;
+use speedtest_rs::{
+    distance, error, speedtest, speedtest_cache, speedtest_config, speedtest_csv,
+    speedtest_servers_config, speedtest_session,
+};
 use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
 use url::Url;
 
+/// Exit status of a run interrupted with Ctrl-C, like a shell reports for SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
@@ -28,10 +31,52 @@ struct Cli {
     #[arg(long, default_value_t = false)]
     no_upload: bool,
 
+    /// Seconds to run the download test for, instead of the speedtest.net configuration
+    #[arg(long, value_parser = parse_seconds)]
+    download_duration: Option<Duration>,
+
+    /// Seconds to run the upload test for, instead of the speedtest.net configuration
+    #[arg(long, value_parser = parse_seconds)]
+    upload_duration: Option<Duration>,
+
+    /// Seconds at the start of each test to leave out of the steady-state throughput
+    #[arg(long, value_parser = parse_warm_up)]
+    warm_up: Option<Duration>,
+
+    /// Display min, max, mean, median, p10, p90 and standard deviation of the
+    /// throughput per sample interval
+    #[arg(long, default_value_t = false)]
+    stats: bool,
+
+    /// Number of latency samples to take from each candidate server
+    #[arg(long, default_value_t = speedtest::DEFAULT_LATENCY_SAMPLES)]
+    latency_samples: usize,
+
+    /// Measure latency while the download and upload tests run and grade bufferbloat
+    #[arg(long, default_value_t = false)]
+    loaded_latency: bool,
+
+    /// Number of servers to try, in order of latency, when the test fails on the
+    /// selected server
+    #[arg(long, default_value_t = speedtest_session::DEFAULT_FALLBACK_SERVERS)]
+    fallback_servers: usize,
+
+    /// Number of servers to probe for latency when selecting the best server
+    #[arg(long, default_value_t = speedtest_session::DEFAULT_LATENCY_CANDIDATES)]
+    candidates: usize,
+
+    /// Probe a geographically diverse sample of nearby servers instead of the closest
+    #[arg(long, default_value_t = false, conflicts_with = "servers")]
+    diverse: bool,
+
     /// Display a list of speedtest.net servers sorted by distance
     #[arg(long, default_value_t = false)]
     list: bool,
 
+    /// Display the latency of the candidate servers, lowest first
+    #[arg(long, default_value_t = false)]
+    list_latency: bool,
+
     /// Generate and provide an URL to the speedtest.net share results image
     #[arg(long, default_value_t = false)]
     share: bool,
@@ -53,6 +98,15 @@ struct Cli {
     #[arg(long, default_value_t = false)]
     csv_header: bool,
 
+    /// Suppress verbose output, only show basic information in JSON format.
+    /// Speeds listed in bit/s and not affected by --bytes.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["csv", "json_lines"])]
+    json: bool,
+
+    /// Like --json, but on a single line so repeated runs can be appended to one file
+    #[arg(long, default_value_t = false, conflicts_with = "csv")]
+    json_lines: bool,
+
     /// Single character delimiter to use in CSV output
     #[arg(long, default_value_t = ',')]
     csv_delimiter: char,
@@ -60,6 +114,99 @@ struct Cli {
     /// Address of speedtest-mini server
     #[arg(short, long)]
     mini: Option<String>,
+
+    /// Specify a server ID to test against. Can be supplied multiple times
+    #[arg(long = "server", value_name = "ID", conflicts_with = "mini")]
+    servers: Vec<u32>,
+
+    /// Only use servers in the country with this ISO 3166 code, e.g. US
+    #[arg(long, value_name = "CODE")]
+    country: Option<String>,
+
+    /// Only use servers whose sponsor contains this text
+    #[arg(long)]
+    sponsor: Option<String>,
+
+    /// Only use servers whose name, usually the city, contains this text
+    #[arg(long)]
+    city: Option<String>,
+
+    /// Only use servers at most this many km away
+    #[arg(long, value_name = "KM")]
+    max_distance: Option<f32>,
+
+    /// Exclude a server from selection. Can be supplied multiple times
+    #[arg(long = "exclude", value_name = "ID")]
+    excluded_servers: Vec<u32>,
+
+    /// Base URL of the speedtest.net configuration, server list and share services
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// URL of the speedtest.net configuration, overriding --base-url
+    #[arg(long)]
+    config_url: Option<String>,
+
+    /// URL of a speedtest.net server list, overriding --base-url. Can be supplied
+    /// multiple times; the lists are merged, the first one taking precedence
+    #[arg(long = "servers-url", value_name = "SERVERS_URL")]
+    servers_urls: Vec<String>,
+
+    /// URL to post share results to, overriding --base-url
+    #[arg(long)]
+    share_url: Option<String>,
+
+    /// Read the speedtest.net configuration from a file instead of downloading it
+    #[arg(long, value_name = "PATH")]
+    config_file: Option<PathBuf>,
+
+    /// Read the server list, in XML or JSON, from a file instead of downloading it
+    #[arg(long, value_name = "PATH")]
+    servers_file: Option<PathBuf>,
+
+    /// Do not read or write the cached configuration and server list
+    #[arg(long, default_value_t = false)]
+    no_cache: bool,
+
+    /// Download the configuration and server list even if they are cached
+    #[arg(long, default_value_t = false, conflicts_with = "no_cache")]
+    refresh_cache: bool,
+
+    /// Seconds the cached configuration and server list are used for
+    #[arg(long, value_name = "SECONDS", default_value_t = speedtest_cache::DEFAULT_CACHE_TTL.as_secs())]
+    cache_ttl: u64,
+}
+
+impl Cli {
+    fn endpoints(&self) -> speedtest::SpeedTestEndpoints {
+        let mut endpoints = self
+            .base_url
+            .as_deref()
+            .map_or_else(speedtest::SpeedTestEndpoints::default, |base_url| {
+                speedtest::SpeedTestEndpoints::with_base_url(base_url)
+            });
+        if let Some(config_url) = &self.config_url {
+            endpoints.configuration = config_url.clone();
+        }
+        if !self.servers_urls.is_empty() {
+            endpoints.server_lists = self.servers_urls.clone();
+        }
+        if let Some(share_url) = &self.share_url {
+            endpoints.share = share_url.clone();
+        }
+        endpoints
+    }
+
+    fn cache(&self) -> Option<speedtest_cache::SpeedTestCache> {
+        if self.no_cache {
+            return None;
+        }
+        speedtest_cache::SpeedTestCache::default_dir().map(|dir| {
+            speedtest_cache::SpeedTestCache::new(dir)
+                .ttl(Duration::from_secs(self.cache_ttl))
+                .refresh(self.refresh_cache)
+        })
+    }
 }
 
 fn main() -> Result<(), error::SpeedTestError> {
@@ -75,26 +222,80 @@ fn main() -> Result<(), error::SpeedTestError> {
         return Ok(());
     }
 
-    let machine_format = matches.csv;
+    let cancellation_token = speedtest::CancellationToken::new();
+    let handler_cancellation_token = cancellation_token.clone();
+    if let Err(_err) = ctrlc::set_handler(move || {
+        // A second Ctrl-C gives up on reporting the partial results.
+        if handler_cancellation_token.is_cancelled() {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        handler_cancellation_token.cancel();
+    }) {
+        info!("Unable to set Ctrl-C handler: {_err:?}");
+    }
 
-    if !matches.simple && !machine_format {
-        println!("Retrieving speedtest.net configuration...");
+    let result = run(&matches, &cancellation_token);
+    if cancellation_token.is_cancelled() {
+        eprintln!("Test interrupted");
+        std::process::exit(INTERRUPTED_EXIT_CODE);
     }
-    let mut config = speedtest::get_configuration()?;
+    result
+}
 
-    let mut server_list_sorted;
-    if let Some(mini) = matches.mini {
-        let mini_url = Url::parse(&mini).unwrap();
+fn run(
+    matches: &Cli,
+    cancellation_token: &speedtest::CancellationToken,
+) -> Result<(), error::SpeedTestError> {
+    let machine_format = matches.csv || matches.json || matches.json_lines;
+
+    let mut builder = speedtest_session::SpeedTest::builder()
+        .endpoints(matches.endpoints())
+        .cancellation_token(cancellation_token.clone())
+        .latency_samples(matches.latency_samples)
+        .loaded_latency(matches.loaded_latency)
+        .fallback_servers(matches.fallback_servers)
+        .latency_candidates(matches.candidates)
+        .exclude_servers(matches.excluded_servers.clone())
+        .server_query(speedtest_servers_config::SpeedTestServerQuery {
+            country_code: matches.country.clone(),
+            sponsor: matches.sponsor.clone(),
+            name: matches.city.clone(),
+            max_distance: matches.max_distance,
+        });
+    if let Some(cache) = matches.cache() {
+        builder = builder.cache(cache);
+    }
+    if let Some(config_file) = &matches.config_file {
+        builder = builder.config_file(config_file);
+    }
+    if let Some(servers_file) = &matches.servers_file {
+        builder = builder.servers_file(servers_file);
+    }
+    if !matches.servers.is_empty() {
+        builder = builder.server_selection(speedtest_session::SpeedTestServerSelection::Ids(
+            matches.servers.clone(),
+        ));
+    } else if matches.diverse {
+        builder = builder.server_selection(speedtest_session::SpeedTestServerSelection::Diverse);
+    }
+    if let Some(download_duration) = matches.download_duration {
+        builder = builder.download_length(download_duration);
+    }
+    if let Some(upload_duration) = matches.upload_duration {
+        builder = builder.upload_length(upload_duration);
+    }
+    if let Some(warm_up) = matches.warm_up {
+        builder = builder.warm_up(warm_up);
+    }
 
-        // matches.value_of("mini").unwrap().to_string()
+    if let Some(mini) = &matches.mini {
+        let mini_url = Url::parse(mini)?;
 
-        let host = mini_url.host().unwrap().to_string();
-        let hostport = mini_url //
-            .port()
-            .map_or_else(
-                || format!("{}:{}", mini_url.host().unwrap(), mini_url.port().unwrap()),
-                |_| host.to_string(),
-            );
+        let host = mini_url
+            .host_str()
+            .ok_or(error::SpeedTestError::ServerParseError)?
+            .to_string();
+        let hostport = format!("{host}:{}", mini_url.port_or_known_default().unwrap_or(80));
 
         let mut path = mini_url.path();
         if path == "/" {
@@ -103,8 +304,9 @@ fn main() -> Result<(), error::SpeedTestError> {
 
         let url = format!("{}://{hostport}{path}", mini_url.scheme());
 
-        server_list_sorted = vec![speedtest::SpeedTestServer {
+        let mini_server = speedtest::SpeedTestServer {
             country: host.to_string(),
+            country_code: String::new(),
             host: hostport,
             id: 0,
             location: distance::EarthLocation {
@@ -115,16 +317,50 @@ fn main() -> Result<(), error::SpeedTestError> {
             name: host.to_string(),
             sponsor: host,
             url,
-        }]
-    } else {
-        if !matches.simple && !machine_format {
-            println!("Retrieving speedtest.net server list...");
+        };
+        // A mini server runs standalone, so speedtest.net may well be unreachable. The
+        // client IP is unknown then.
+        if matches.config_file.is_none() {
+            builder = builder.config(speedtest_config::SpeedTestConfig::default());
         }
-        let server_list = speedtest::get_server_list_with_config(&config)?;
-        server_list_sorted = server_list.servers_sorted_by_distance(&config);
+        builder = builder.server_selection(speedtest_session::SpeedTestServerSelection::Fixed(
+            mini_server,
+        ));
+    }
 
+    // Only announce what is downloaded rather than read from a file.
+    if !matches.simple && !machine_format && matches.mini.is_none() {
+        if matches.config_file.is_none() {
+            println!("Retrieving speedtest.net configuration...");
+        }
+        if matches.servers_file.is_none() {
+            println!("Retrieving speedtest.net server list...");
+        }
+    }
+    let mut session = builder.build()?;
+
+    if matches.list_latency {
+        for result in session.rank_servers()? {
+            let server = &result.server;
+            let latency = match &result.error {
+                Some(error) => format!("failed: {error}"),
+                None => format!(
+                    "{} (jitter {}, loss {:.1}%)",
+                    format_latency(Some(result.latency)),
+                    format_latency(Some(result.jitter())),
+                    result.loss_percent(),
+                ),
+            };
+            println!(
+                "{:4}) {} ({}, {}): {latency}",
+                server.id, server.sponsor, server.name, server.country,
+            );
+        }
+        return Ok(());
+    }
+    if matches.mini.is_none() {
         if matches.list {
-            for server in server_list_sorted {
+            for server in session.servers() {
                 println!(
                     "{:4}) {} ({}, {}) [{}]",
                     server.id,
@@ -141,18 +377,17 @@ fn main() -> Result<(), error::SpeedTestError> {
         if !matches.simple && !machine_format {
             println!(
                 "Testing from {} ({})...",
-                config.client.isp, config.client.ip
+                session.config().client.isp,
+                session
+                    .config()
+                    .client
+                    .ip
+                    .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
             );
             println!("Selecting best server based on latency...");
         }
-
-        info!("Five Closest Servers");
-        server_list_sorted.truncate(5);
-        for _server in &server_list_sorted {
-            info!("Close Server: {_server:?}");
-        }
     }
-    let latency_test_result = speedtest::get_best_server_based_on_latency(&server_list_sorted[..])?;
+    let latency_test_result = session.select_server()?;
 
     if !machine_format {
         if !matches.simple {
@@ -167,6 +402,14 @@ fn main() -> Result<(), error::SpeedTestError> {
                 latency_test_result.latency.as_millis(),
                 latency_test_result.latency.as_micros() % 1000,
             );
+            println!(
+                "Latency: min {}, median {}, max {}, jitter {}, loss {:.1}%",
+                format_latency(latency_test_result.min()),
+                format_latency(latency_test_result.median()),
+                format_latency(latency_test_result.max()),
+                format_latency(Some(latency_test_result.jitter())),
+                latency_test_result.loss_percent(),
+            );
         } else {
             println!(
                 "Ping: {}.{} ms",
@@ -176,107 +419,108 @@ fn main() -> Result<(), error::SpeedTestError> {
         }
     }
 
-    let best_server = latency_test_result.server;
-
-    let download_measurement;
-    let inner_download_measurement;
-
     if !matches.no_download {
-        if !matches.simple && !machine_format {
+        let download_measurement = if !matches.simple && !machine_format {
             print!("Testing download speed");
-            inner_download_measurement = speedtest::test_download_with_progress_and_config(
-                best_server,
-                print_dot,
-                &mut config,
-            )?;
+            io::stdout().flush()?;
+            let download_measurement =
+                session.test_download(print_progress("Testing download speed", matches.bytes))?;
             println!();
+            download_measurement
         } else {
-            inner_download_measurement =
-                speedtest::test_download_with_progress_and_config(best_server, || {}, &mut config)?;
-        }
+            session.test_download(|_| {})?
+        };
 
         if !machine_format {
             if matches.bytes {
                 println!(
                     "Download: {:.2} Mbyte/s",
-                    ((inner_download_measurement.kbps() / 8) as f32 / 1000.00)
+                    ((download_measurement.kbps() / 8) as f32 / 1000.00)
                 );
             } else {
                 println!(
                     "Download: {:.2} Mbit/s",
-                    (inner_download_measurement.kbps()) as f32 / 1000.00
+                    (download_measurement.kbps()) as f32 / 1000.00
                 );
             }
+            print_steady_state("Download", download_measurement, matches.bytes);
+            if matches.stats {
+                print_statistics("Download", download_measurement, matches.bytes);
+            }
         }
-        download_measurement = Some(&inner_download_measurement);
-    } else {
-        download_measurement = None;
     }
 
-    let upload_measurement;
-    let inner_upload_measurement;
-
-    if !matches.no_upload {
-        if !matches.simple && !machine_format {
+    if !matches.no_upload && !cancellation_token.is_cancelled() {
+        let upload_measurement = if !matches.simple && !machine_format {
             print!("Testing upload speed");
-            inner_upload_measurement =
-                speedtest::test_upload_with_progress_and_config(best_server, print_dot, &config)?;
+            io::stdout().flush()?;
+            let upload_measurement =
+                session.test_upload(print_progress("Testing upload speed", matches.bytes))?;
             println!();
+            upload_measurement
         } else {
-            inner_upload_measurement =
-                speedtest::test_upload_with_progress_and_config(best_server, || {}, &config)?;
-        }
+            session.test_upload(|_| {})?
+        };
 
         if !machine_format {
             if matches.bytes {
                 println!(
                     "Upload: {:.2} Mbyte/s",
-                    ((inner_upload_measurement.kbps() / 8) as f32 / 1000.00)
+                    ((upload_measurement.kbps() / 8) as f32 / 1000.00)
                 );
             } else {
                 println!(
                     "Upload: {:.2} Mbit/s",
-                    (inner_upload_measurement.kbps() as f32 / 1000.00)
+                    (upload_measurement.kbps() as f32 / 1000.00)
                 );
             }
+            print_steady_state("Upload", upload_measurement, matches.bytes);
+            if matches.stats {
+                print_statistics("Upload", upload_measurement, matches.bytes);
+            }
         }
-        upload_measurement = Some(&inner_upload_measurement);
-    } else {
-        upload_measurement = None;
     }
 
-    let speedtest_result = speedtest::SpeedTestResult {
-        download_measurement,
-        upload_measurement,
-        server: best_server,
-        latency_measurement: &latency_test_result,
-    };
+    if matches.share && !cancellation_token.is_cancelled() {
+        session.share()?;
+    }
+
+    let speedtest_result = session.result()?;
+    let best_server = &speedtest_result.server;
+    let download_measurement = speedtest_result.download_measurement.as_ref();
+    let upload_measurement = speedtest_result.upload_measurement.as_ref();
 
     if matches.csv {
+        let latency_measurement = &speedtest_result.latency_measurement;
         let speedtest_csv_result = SpeedTestCsvResult {
             server_id: &best_server.id.to_string(),
             sponsor: &best_server.sponsor,
             server_name: &best_server.name,
-            timestamp: &Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
-            distance: &(latency_test_result
-                .server
+            timestamp: &speedtest_result
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            distance: &(best_server
                 .distance
                 .map_or("".to_string(), |d| format!("{d:.14}")))[..],
             ping: &format!(
                 "{}.{}",
-                latency_test_result.latency.as_millis(),
-                latency_test_result.latency.as_micros() % 1000
+                speedtest_result.latency_measurement.latency.as_millis(),
+                speedtest_result.latency_measurement.latency.as_micros() % 1000
             ),
             download: &download_measurement
                 .map_or(0.0, |x| x.bps_f64())
                 .to_string(),
             upload: &upload_measurement.map_or(0.0, |x| x.bps_f64()).to_string(),
-            share: &if matches.share {
-                speedtest::get_share_url(&speedtest_result)?
-            } else {
-                "".to_string()
-            },
-            ip_address: &config.client.ip.to_string(),
+            share: speedtest_result.share_url.as_deref().unwrap_or_default(),
+            ip_address: &speedtest_result
+                .client
+                .ip
+                .map_or_else(String::new, |ip| ip.to_string()),
+            ping_min: &csv_latency(latency_measurement.min()),
+            ping_max: &csv_latency(latency_measurement.max()),
+            ping_median: &csv_latency(latency_measurement.median()),
+            jitter: &csv_latency(Some(latency_measurement.jitter())),
+            packet_loss: &latency_measurement.loss_percent().to_string(),
         };
         let mut wtr = csv::WriterBuilder::new()
             .has_headers(false)
@@ -287,13 +531,60 @@ fn main() -> Result<(), error::SpeedTestError> {
         return Ok(());
     }
 
-    if matches.share && !machine_format {
-        info!("Share Request {speedtest_result:?}",);
+    if matches.json || matches.json_lines {
+        let speedtest_json_result = SpeedTestJsonResult::from_result(&speedtest_result);
+        let mut stdout = io::stdout();
+        if matches.json {
+            serde_json::to_writer_pretty(&mut stdout, &speedtest_json_result)?;
+        } else {
+            serde_json::to_writer(&mut stdout, &speedtest_json_result)?;
+        }
+        writeln!(stdout)?;
+        return Ok(());
+    }
+
+    for attempt in &speedtest_result.failed_attempts {
         println!(
-            "Share results: {}",
-            speedtest::get_share_url(&speedtest_result)?
+            "{:?} test failed on {} ({}), used the next server instead: {}",
+            attempt.phase, attempt.server.sponsor, attempt.server.name, attempt.error,
         );
     }
+    for (label, phase_server) in [
+        ("Download", &speedtest_result.download_server),
+        ("Upload", &speedtest_result.upload_server),
+    ] {
+        if let Some(phase_server) = phase_server
+            .as_ref()
+            .filter(|phase_server| phase_server.url != speedtest_result.server.url)
+        {
+            println!(
+                "{label} measured on {} ({})",
+                phase_server.sponsor, phase_server.name
+            );
+        }
+    }
+    for (label, loaded_latency) in [
+        ("download", &speedtest_result.download_loaded_latency),
+        ("upload", &speedtest_result.upload_loaded_latency),
+    ] {
+        if let Some(loaded_latency) = loaded_latency {
+            println!(
+                "Latency during {label}: median {}, max {}, jitter {}, loss {:.1}%",
+                format_latency(loaded_latency.median()),
+                format_latency(loaded_latency.max()),
+                format_latency(Some(loaded_latency.jitter())),
+                loaded_latency.loss_percent(),
+            );
+        }
+    }
+    if let Some(grade) = speedtest_result.bufferbloat_grade() {
+        println!("Bufferbloat grade: {grade}");
+    }
+
+    if let (Some(share_url), false) = (&speedtest_result.share_url, machine_format) {
+        info!("Share Request {speedtest_result:?}",);
+        println!("Share results: {share_url}");
+    }
 
     if let (Some(download_measurement), Some(upload_measurement)) =
         (download_measurement, upload_measurement)
@@ -308,7 +599,86 @@ fn main() -> Result<(), error::SpeedTestError> {
     Ok(())
 }
 
-fn print_dot() {
-    print!(".");
-    io::stdout().flush().unwrap();
+/// Parse a positive, finite number of seconds.
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    let duration = parse_warm_up(seconds)?;
+    if duration.is_zero() {
+        return Err("value is zero".to_owned());
+    }
+    Ok(duration)
+}
+
+/// Parse a non-negative, finite number of seconds, where zero turns the warm-up off.
+fn parse_warm_up(seconds: &str) -> Result<Duration, String> {
+    let seconds: f64 = seconds.parse().map_err(|err| format!("{err}"))?;
+    Duration::try_from_secs_f64(seconds).map_err(|err| format!("{err}"))
+}
+
+fn format_latency(latency: Option<Duration>) -> String {
+    latency.map_or_else(
+        || "n/a".to_string(),
+        |latency| format!("{:.3} ms", latency.as_secs_f64() * 1000.0),
+    )
+}
+
+fn csv_latency(latency: Option<Duration>) -> String {
+    latency.map_or_else(String::new, |latency| {
+        format!("{:.3}", latency.as_secs_f64() * 1000.0)
+    })
+}
+
+/// Print the throughput after the warm-up, if a warm-up was configured.
+fn print_steady_state(label: &str, measurement: &speedtest::SpeedMeasurement, bytes: bool) {
+    if let Some(bps) = measurement.steady_state_bps() {
+        if bytes {
+            println!(
+                "{label} (steady state): {:.2} Mbyte/s",
+                bps / 8.0 / 1_000_000.0
+            );
+        } else {
+            println!("{label} (steady state): {:.2} Mbit/s", bps / 1_000_000.0);
+        }
+    }
+}
+
+/// Print the summary of the throughput per sample interval.
+fn print_statistics(label: &str, measurement: &speedtest::SpeedMeasurement, bytes: bool) {
+    let Some(statistics) = &measurement.statistics else {
+        println!("{label} statistics: not enough samples");
+        return;
+    };
+    let (scale, unit) = if bytes {
+        (8.0 * 1_000_000.0, "Mbyte/s")
+    } else {
+        (1_000_000.0, "Mbit/s")
+    };
+    println!(
+        "{label} statistics ({unit}): min {:.2}, p10 {:.2}, median {:.2}, p90 {:.2}, max {:.2}, mean {:.2}, stddev {:.2}",
+        statistics.min / scale,
+        statistics.p10 / scale,
+        statistics.median / scale,
+        statistics.p90 / scale,
+        statistics.max / scale,
+        statistics.mean / scale,
+        statistics.stddev / scale,
+    );
+}
+
+/// Progress callback that keeps rewriting the current line with the throughput so far.
+fn print_progress(
+    label: &'static str,
+    bytes: bool,
+) -> impl Fn(&speedtest::SpeedTestProgress) + Send + Sync + 'static {
+    move |progress| {
+        let (speed, unit) = if bytes {
+            (progress.average_bps / 8.0 / 1_000_000.0, "Mbyte/s")
+        } else {
+            (progress.average_bps / 1_000_000.0, "Mbit/s")
+        };
+        print!(
+            "\r{label}: {speed:.2} {unit} over {} connection(s)   ",
+            progress.active_connections
+        );
+        io::stdout().flush().unwrap();
+    }
 }
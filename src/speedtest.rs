@@ -1,10 +1,13 @@
 #![allow(dead_code)]
 
 use std::{
-    io::Read,
+    io::{self, Read},
     path::Path,
-    sync::atomic::{AtomicBool, Ordering},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 #[cfg(feature = "log")]
@@ -19,15 +22,23 @@ use reqwest::Url;
 
 use crate::distance::EarthLocation;
 use crate::error::SpeedTestError;
-use crate::speedtest_config::SpeedTestConfig;
+use crate::speedtest_cache::SpeedTestCache;
+use crate::speedtest_config::{SpeedTestClientConfig, SpeedTestConfig};
+use crate::speedtest_loaded_latency::BufferbloatGrade;
 use crate::speedtest_servers_config::SpeedTestServersConfig;
+use crate::statistics::{self, Statistics};
+use chrono::{DateTime, Utc};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-const ST_USER_AGENT: &str = concat!("reqwest/speedtest-rs ", env!("CARGO_PKG_VERSION"));
+pub(crate) const ST_USER_AGENT: &str = concat!("reqwest/speedtest-rs ", env!("CARGO_PKG_VERSION"));
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SpeedTestServer {
     pub country: String,
+    /// ISO 3166 country code, e.g. `US`
+    #[serde(default)]
+    pub country_code: String,
     pub host: String,
     pub id: u32,
     pub location: EarthLocation,
@@ -37,173 +48,781 @@ pub struct SpeedTestServer {
     pub url: String,
 }
 
-pub fn download_configuration() -> Result<Response, SpeedTestError> {
-    info!("Downloading Configuration from speedtest.net");
+/// Locations of the speedtest.net services that are not test servers themselves.
+///
+/// The defaults point at speedtest.net, but every endpoint can be redirected to a
+/// mirror or a local stand-in.
+#[derive(Clone, Debug)]
+pub struct SpeedTestEndpoints {
+    pub configuration: String,
+    /// Server list sources, downloaded concurrently and merged by server ID. Servers
+    /// from earlier sources take precedence.
+    pub server_lists: Vec<String>,
+    pub share: String,
+    pub share_result: String,
+}
+
+impl SpeedTestEndpoints {
+    pub fn with_base_url(base_url: &str) -> SpeedTestEndpoints {
+        let base_url = base_url.trim_end_matches('/');
+        SpeedTestEndpoints {
+            configuration: format!("{base_url}/speedtest-config.php"),
+            server_lists: vec![
+                format!("{base_url}/speedtest-servers-static.php"),
+                format!("{base_url}/speedtest-servers.php"),
+            ],
+            share: format!("{base_url}/api/api.php"),
+            share_result: format!("{base_url}/result"),
+        }
+    }
+}
 
-    let mut _server = mockito::Server::new();
+impl Default for SpeedTestEndpoints {
+    fn default() -> Self {
+        let mut endpoints = SpeedTestEndpoints::with_base_url("http://www.speedtest.net");
+        // Mirrors, so that the server list can still be merged from somewhere when
+        // www.speedtest.net is down.
+        endpoints.server_lists.extend([
+            "http://c.speedtest.net/speedtest-servers-static.php".to_owned(),
+            "http://c.speedtest.net/speedtest-servers.php".to_owned(),
+        ]);
+        endpoints
+    }
+}
 
-    #[cfg(not(test))]
-    let url = "http://www.speedtest.net/speedtest-config.php";
-    #[cfg(test)]
-    let url = &format!("{}/speedtest-config.php", &_server.url());
+pub fn download_configuration(endpoints: &SpeedTestEndpoints) -> Result<Response, SpeedTestError> {
+    info!("Downloading Configuration from {}", endpoints.configuration);
 
     let client = Client::new();
     // Creating an outgoing request.
     let res = client
-        .get(url)
+        .get(&endpoints.configuration)
         .header(CONNECTION, "close")
         .header(USER_AGENT, ST_USER_AGENT.to_owned())
         .send()?;
-    info!("Downloaded Configuration from speedtest.net");
+    info!("Downloaded Configuration from {}", endpoints.configuration);
     Ok(res)
 }
 
-pub fn get_configuration() -> Result<SpeedTestConfig, SpeedTestError> {
-    let config_body = download_configuration()?;
+/// Download the configuration, or take it from `cache` while it is fresh.
+pub fn get_configuration(
+    endpoints: &SpeedTestEndpoints,
+    cache: Option<&SpeedTestCache>,
+) -> Result<SpeedTestConfig, SpeedTestError> {
+    if let Some(spt_config) = cached_configuration(endpoints, cache) {
+        return Ok(spt_config);
+    }
+    let config_body = download_configuration(endpoints)?;
     info!("Parsing Configuration");
-    let spt_config = SpeedTestConfig::parse(&(config_body.text()?))?;
+    let config_text = config_body.text()?;
+    let spt_config = SpeedTestConfig::parse(&config_text)?;
     info!("Parsed Configuration");
+    cache_response(cache, &endpoints.configuration, None, &config_text);
     Ok(spt_config)
 }
 
-pub fn download_server_list() -> Result<Response, SpeedTestError> {
-    info!("Download Server List");
-    let mut _server = mockito::Server::new();
+pub(crate) fn cached_configuration(
+    endpoints: &SpeedTestEndpoints,
+    cache: Option<&SpeedTestCache>,
+) -> Option<SpeedTestConfig> {
+    let entry = cache?.get(&endpoints.configuration)?;
+    info!("Using cached Configuration from {}", entry.timestamp);
+    SpeedTestConfig::parse(&entry.body).ok()
+}
 
-    #[cfg(not(test))]
-    let url = "http://www.speedtest.net/speedtest-servers.php";
-    #[cfg(test)]
-    let url = &format!("{}/speedtest-servers.php", &_server.url());
+pub(crate) fn cached_server_list(
+    url: &str,
+    cache: Option<&SpeedTestCache>,
+    config: &SpeedTestConfig,
+) -> Option<SpeedTestServersConfig> {
+    let entry = cache?.get(url)?;
+    info!("Using cached Server List from {}", entry.timestamp);
+    SpeedTestServersConfig::parse_with_content_type(
+        &entry.body,
+        entry.content_type.as_deref(),
+        config,
+    )
+    .ok()
+}
+
+/// Store a parsed response in `cache`, if any. Failing to do so is not an error.
+pub(crate) fn cache_response(
+    cache: Option<&SpeedTestCache>,
+    url: &str,
+    content_type: Option<&str>,
+    body: &str,
+) {
+    if let Some(cache) = cache {
+        if let Err(_err) = cache.put(url, content_type, body) {
+            info!("Caching {url} failed: {_err:?}");
+        }
+    }
+}
+
+pub fn download_server_list(url: &str) -> Result<Response, SpeedTestError> {
+    info!("Download Server List from {url}");
 
     let client = Client::new();
     let server_res = client
         .get(url)
         .header(CONNECTION, "close")
         .header(USER_AGENT, ST_USER_AGENT)
-        .send()?;
+        .send()?
+        .error_for_status()?;
     info!("Downloaded Server List");
     Ok(server_res)
 }
 
+/// Download and merge the server lists of all sources in `endpoints`, taking those
+/// still fresh in `cache` from there. Fails only if none of them could be retrieved.
 pub fn get_server_list_with_config(
+    endpoints: &SpeedTestEndpoints,
+    cache: Option<&SpeedTestCache>,
     config: &SpeedTestConfig,
 ) -> Result<SpeedTestServersConfig, SpeedTestError> {
-    let config_body = download_server_list()?;
+    let server_lists = std::thread::scope(|scope| {
+        let handles: Vec<_> = endpoints
+            .server_lists
+            .iter()
+            .map(|url| scope.spawn(move || get_server_list_from_url(url, cache, config)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or(Err(SpeedTestError::ServerParseError))
+            })
+            .collect()
+    });
+    merge_server_lists(server_lists)
+}
+
+/// Merge server lists in order of precedence, skipping the ones that failed.
+pub(crate) fn merge_server_lists(
+    server_lists: Vec<Result<SpeedTestServersConfig, SpeedTestError>>,
+) -> Result<SpeedTestServersConfig, SpeedTestError> {
+    let mut merged: Option<SpeedTestServersConfig> = None;
+    let mut first_error = None;
+    for server_list in server_lists {
+        match server_list {
+            Ok(server_list) => match &mut merged {
+                Some(merged) => merged.merge(server_list),
+                None => merged = Some(server_list),
+            },
+            Err(err) => {
+                info!("Server list source failed: {err:?}");
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    match (merged, first_error) {
+        (Some(merged), _) => Ok(merged),
+        (None, Some(err)) => Err(err),
+        (None, None) => Ok(SpeedTestServersConfig { servers: vec![] }),
+    }
+}
+
+pub fn get_server_list_from_url(
+    url: &str,
+    cache: Option<&SpeedTestCache>,
+    config: &SpeedTestConfig,
+) -> Result<SpeedTestServersConfig, SpeedTestError> {
+    if let Some(server_list) = cached_server_list(url, cache, config) {
+        return Ok(server_list);
+    }
+    let config_body = download_server_list(url)?;
     info!("Parsing Server List");
+    let content_type = config_body
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
     let server_config_string = config_body.text()?;
 
+    let server_list = SpeedTestServersConfig::parse_with_content_type(
+        &server_config_string,
+        content_type.as_deref(),
+        config,
+    )?;
     info!("Parsed Server List");
-    SpeedTestServersConfig::parse_with_config(&server_config_string, config)
+    cache_response(cache, url, content_type.as_deref(), &server_config_string);
+    Ok(server_list)
 }
 
-#[derive(Debug)]
-pub struct SpeedTestLatencyTestResult<'a> {
-    pub server: &'a SpeedTestServer,
+/// Read the configuration from a file saved from `speedtest-config.php`, e.g. for use
+/// where speedtest.net is unreachable.
+pub fn get_configuration_from_file(path: &Path) -> Result<SpeedTestConfig, SpeedTestError> {
+    info!("Reading Configuration from {}", path.display());
+    SpeedTestConfig::parse(&std::fs::read_to_string(path)?)
+}
+
+/// Read a server list in either format from a file. Files ending in `.json` or `.xml`
+/// are parsed as such, others by their first character.
+pub fn get_server_list_from_file(
+    path: &Path,
+    config: &SpeedTestConfig,
+) -> Result<SpeedTestServersConfig, SpeedTestError> {
+    info!("Reading Server List from {}", path.display());
+    let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => Some("application/json"),
+        Some("xml") => Some("text/xml"),
+        _ => None,
+    };
+    SpeedTestServersConfig::parse_with_content_type(
+        &std::fs::read_to_string(path)?,
+        content_type,
+        config,
+    )
+}
+
+/// Number of latency samples taken per server unless configured otherwise.
+pub const DEFAULT_LATENCY_SAMPLES: usize = 3;
+
+/// Number of servers probed for latency at the same time.
+pub const MAX_CONCURRENT_LATENCY_PROBES: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpeedTestLatencyTestResult {
+    pub server: SpeedTestServer,
+    /// Mean of `samples`
     pub latency: Duration,
+    /// Trip time of each successful sample, i.e. half of its round trip.
+    #[serde(default)]
+    pub samples: Vec<Duration>,
+    /// Samples whose request failed
+    #[serde(default)]
+    pub failed_samples: usize,
+    /// Why the server could not be sampled at all, if it couldn't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
+impl SpeedTestLatencyTestResult {
+    /// Summarize the round trip times of one server's samples.
+    pub(crate) fn from_round_trips(
+        server: SpeedTestServer,
+        round_trips: &[Duration],
+        failed_samples: usize,
+    ) -> SpeedTestLatencyTestResult {
+        SpeedTestLatencyTestResult {
+            server,
+            latency: trip_latency(round_trips),
+            samples: round_trips
+                .iter()
+                .map(|&round_trip| round_trip / 2)
+                .collect(),
+            failed_samples,
+            error: None,
+        }
+    }
+
+    /// Result for a server that could not be sampled.
+    pub(crate) fn from_error(
+        server: SpeedTestServer,
+        failed_samples: usize,
+        error: String,
+    ) -> SpeedTestLatencyTestResult {
+        SpeedTestLatencyTestResult {
+            server,
+            latency: Duration::ZERO,
+            samples: vec![],
+            failed_samples,
+            error: Some(error),
+        }
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.samples.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    pub fn median(&self) -> Option<Duration> {
+        let mut samples: Vec<f64> = self.samples.iter().map(Duration::as_secs_f64).collect();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_by(f64::total_cmp);
+        Some(Duration::from_secs_f64(statistics::percentile(
+            &samples, 50.0,
+        )))
+    }
+
+    /// Mean absolute difference between consecutive samples.
+    pub fn jitter(&self) -> Duration {
+        if self.samples.len() < 2 {
+            return Duration::ZERO;
+        }
+        let total: Duration = self
+            .samples
+            .windows(2)
+            .map(|pair| pair[0].abs_diff(pair[1]))
+            .sum();
+        total / (self.samples.len() - 1) as u32
+    }
+
+    /// Percentage of samples that failed.
+    pub fn loss_percent(&self) -> f64 {
+        let total = self.samples.len() + self.failed_samples;
+        if total == 0 {
+            return 0.0;
+        }
+        self.failed_samples as f64 * 100.0 / total as f64
+    }
+}
+
+/// Stops running tests early when cancelled, e.g. from a Ctrl-C handler.
+///
+/// Clones share the same state, so one clone can be handed to a signal handler while
+/// another is passed to the test functions.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How candidate servers are probed for latency.
+#[derive(Clone, Debug)]
+pub struct SpeedTestLatencyOptions {
+    /// Samples taken from each server
+    pub samples: usize,
+    /// Time after which a server is not sampled anymore; pending samples count as lost.
+    pub server_timeout: Duration,
+    /// Time after which no server is sampled anymore.
+    pub deadline: Duration,
+}
+
+impl Default for SpeedTestLatencyOptions {
+    fn default() -> Self {
+        SpeedTestLatencyOptions {
+            samples: DEFAULT_LATENCY_SAMPLES,
+            server_timeout: Duration::from_secs(5),
+            deadline: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Takes `sample_count` latency samples from each server and returns the one with the
+/// lowest latency. Failed samples count as loss; servers without a successful sample
+/// are skipped.
 pub fn get_best_server_based_on_latency(
     servers: &[SpeedTestServer],
+    sample_count: usize,
+    cancellation_token: &CancellationToken,
 ) -> Result<SpeedTestLatencyTestResult, SpeedTestError> {
-    info!("Testing for fastest server");
-    let client = Client::new();
-    let mut fastest_server = None;
-    let mut fastest_latency = Duration::new(u64::MAX, 0);
+    let options = SpeedTestLatencyOptions {
+        samples: sample_count,
+        ..SpeedTestLatencyOptions::default()
+    };
+    let fastest = rank_servers_by_latency(servers, &options, cancellation_token)?
+        .into_iter()
+        .find(|result| result.error.is_none());
+    info!("Fastest Server : {fastest:?}");
+    fastest.ok_or(SpeedTestError::LatencyTestClosestError)
+}
+
+/// Probes up to [`MAX_CONCURRENT_LATENCY_PROBES`] servers at a time and returns every one
+/// of them, fewest lost samples and then lowest latency first.
+/// Servers without a successful sample come last, with the error they failed with.
+pub fn rank_servers_by_latency(
+    servers: &[SpeedTestServer],
+    options: &SpeedTestLatencyOptions,
+    cancellation_token: &CancellationToken,
+) -> Result<Vec<SpeedTestLatencyTestResult>, SpeedTestError> {
+    info!("Testing latency of {} servers", servers.len());
     // Return error if no servers are available.
     if servers.is_empty() {
         return Err(SpeedTestError::LatencyTestNoServerError);
     }
-    'server_loop: for server in servers {
-        let path = Path::new(&server.url);
-        let latency_path = format!(
-            "{}/latency.txt",
-            path.parent()
-                .ok_or(SpeedTestError::LatencyTestInvalidPath)?
-                .display()
-        );
-        info!("Downloading: {:?}", latency_path);
-        let mut latency_measurements = vec![];
-        for _ in 0..3 {
-            let start_time = SystemTime::now();
-            let res = client
-                .get(&latency_path)
-                .header(CONNECTION, "close")
-                .header(USER_AGENT, ST_USER_AGENT.to_owned())
-                .send();
-            if res.is_err() {
-                // Log the error and continue to the next server.
-                info!("Error: {:?}", res.err());
-                continue 'server_loop;
-            }
-            let _ = res?.bytes()?.last();
-            let latency_measurement = SystemTime::now().duration_since(start_time)?;
-            info!("Sampled {} ms", latency_measurement.as_millis());
-            latency_measurements.push(latency_measurement);
-        }
-        // Divide by the double to get the non-RTT time but the trip time.
-        // NOT PING or RTT
-        // https://github.com/sivel/speedtest-cli/pull/199
-        let latency = latency_measurements
-            .iter()
-            .fold(Duration::new(0, 0), |a, &i| a + i)
-            / ((latency_measurements.len() as u32) * 2);
-        info!("Trip calculated to {} ms", latency.as_millis());
+    let client = Client::new();
+    let deadline = Instant::now() + options.deadline;
+    let next_server = AtomicUsize::new(0);
+    let mut results = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..servers.len().min(MAX_CONCURRENT_LATENCY_PROBES))
+            .map(|_| {
+                let (client, next_server) = (&client, &next_server);
+                scope.spawn(move || {
+                    let mut results = vec![];
+                    loop {
+                        let index = next_server.fetch_add(1, Ordering::Relaxed);
+                        let Some(server) = servers.get(index) else {
+                            return Ok(results);
+                        };
+                        let result =
+                            probe_latency(client, server, options, deadline, cancellation_token)?;
+                        results.push((index, result));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("latency probe panicked"))
+            .collect::<Result<Vec<Vec<_>>, SpeedTestError>>()
+    })?
+    .concat();
+    // Keep the order of the server list among servers with the same latency.
+    results.sort_unstable_by_key(|(index, _)| *index);
+    Ok(rank_latency_results(
+        results.into_iter().map(|(_, result)| result).collect(),
+    ))
+}
 
-        if latency < fastest_latency {
-            fastest_server = Some(server);
-            fastest_latency = latency;
+fn probe_latency(
+    client: &Client,
+    server: &SpeedTestServer,
+    options: &SpeedTestLatencyOptions,
+    deadline: Instant,
+    cancellation_token: &CancellationToken,
+) -> Result<SpeedTestLatencyTestResult, SpeedTestError> {
+    let latency_path = match latency_url(server) {
+        Ok(latency_path) => latency_path,
+        // The only way this fails is a server URL without a path to replace.
+        Err(_) => {
+            return Ok(SpeedTestLatencyTestResult::from_error(
+                server.clone(),
+                0,
+                format!("Invalid server URL {}", server.url),
+            ))
+        }
+    };
+    info!("Downloading: {:?}", latency_path);
+    let server_deadline = deadline.min(Instant::now() + options.server_timeout);
+    let mut latency_measurements = vec![];
+    let mut failed_samples = 0;
+    let mut last_error = None;
+    for _ in 0..options.samples.max(1) {
+        if cancellation_token.is_cancelled() {
+            return Err(SpeedTestError::Cancelled);
         }
+        let start_time = Instant::now();
+        let Some(remaining) = sample_timeout(server_deadline, start_time) else {
+            failed_samples += 1;
+            continue;
+        };
+        let res = client
+            .get(&latency_path)
+            .header(CONNECTION, "close")
+            .header(USER_AGENT, ST_USER_AGENT.to_owned())
+            .timeout(remaining)
+            .send()
+            .and_then(|res| res.bytes());
+        if let Err(err) = res {
+            info!("Error: {err:?}");
+            failed_samples += 1;
+            last_error = Some(err.to_string());
+            continue;
+        }
+        let latency_measurement = start_time.elapsed();
+        info!("Sampled {} ms", latency_measurement.as_millis());
+        latency_measurements.push(latency_measurement);
     }
-    info!(
-        "Fastest Server @ {}ms : {fastest_server:?}",
-        fastest_latency.as_millis(),
-    );
-    Ok(SpeedTestLatencyTestResult {
-        server: fastest_server.ok_or(SpeedTestError::LatencyTestClosestError)?,
-        latency: fastest_latency,
-    })
+    Ok(latency_result(
+        server,
+        &latency_measurements,
+        failed_samples,
+        last_error,
+    ))
 }
 
-#[derive(Debug)]
+/// Summarize the samples of a server, or record why it could not be sampled.
+pub(crate) fn latency_result(
+    server: &SpeedTestServer,
+    round_trips: &[Duration],
+    failed_samples: usize,
+    last_error: Option<String>,
+) -> SpeedTestLatencyTestResult {
+    if round_trips.is_empty() {
+        let error = last_error.unwrap_or_else(|| "Timed out before the first sample".to_owned());
+        return SpeedTestLatencyTestResult::from_error(server.clone(), failed_samples, error);
+    }
+    let result =
+        SpeedTestLatencyTestResult::from_round_trips(server.clone(), round_trips, failed_samples);
+    info!("Trip calculated to {} ms", result.latency.as_millis());
+    result
+}
+
+/// Time left for a sample started at `now`, if any.
+pub(crate) fn sample_timeout(deadline: Instant, now: Instant) -> Option<Duration> {
+    deadline
+        .checked_duration_since(now)
+        .filter(|remaining| !remaining.is_zero())
+}
+
+/// Sort by packet loss and then latency, with the servers that failed last.
+pub(crate) fn rank_latency_results(
+    mut results: Vec<SpeedTestLatencyTestResult>,
+) -> Vec<SpeedTestLatencyTestResult> {
+    results.sort_by_key(|result| {
+        (
+            result.error.is_some(),
+            result.failed_samples,
+            result.latency,
+        )
+    });
+    results
+}
+
+pub(crate) fn latency_url(server: &SpeedTestServer) -> Result<String, SpeedTestError> {
+    let path = Path::new(&server.url);
+    Ok(format!(
+        "{}/latency.txt",
+        path.parent()
+            .ok_or(SpeedTestError::LatencyTestInvalidPath)?
+            .display()
+    ))
+}
+
+pub(crate) fn trip_latency(latency_measurements: &[Duration]) -> Duration {
+    // Divide by the double to get the non-RTT time but the trip time.
+    // NOT PING or RTT
+    // https://github.com/sivel/speedtest-cli/pull/199
+    if latency_measurements.is_empty() {
+        return Duration::ZERO;
+    }
+    latency_measurements
+        .iter()
+        .fold(Duration::new(0, 0), |a, &i| a + i)
+        / ((latency_measurements.len() as u32) * 2)
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SpeedMeasurement {
     pub size: usize,
     pub duration: Duration,
+    /// Per-request breakdown. Only recorded by the upload test.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requests: Vec<SpeedTestRequestMeasurement>,
+    /// Length of each entry in `samples`
+    #[serde(default)]
+    pub sample_interval: Duration,
+    /// Bytes transferred across all connections in each consecutive sample interval
+    /// since the start of the test.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<usize>,
+    /// Initial part of the test left out of [`SpeedMeasurement::steady_state_bps`]
+    #[serde(default)]
+    pub warm_up: Duration,
+    /// Summary of [`SpeedMeasurement::interval_bps`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<Statistics>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SpeedTestRequestMeasurement {
+    /// Planned body size
+    pub size: usize,
+    /// Bytes handed to the connection before the request finished or the test ended
+    pub sent: usize,
+    pub duration: Duration,
+    /// Failed requests don't count towards the measurement.
+    pub error: Option<String>,
 }
 
 impl SpeedMeasurement {
+    /// 0 when the test ended in under a millisecond.
     pub fn kbps(&self) -> u32 {
-        (self.size as u32 * 8) / self.duration.as_millis() as u32
+        (self.size as u32 * 8)
+            .checked_div(self.duration.as_millis() as u32)
+            .unwrap_or(0)
     }
 
+    /// 0 when the test ended in under a millisecond.
     pub fn bps_f64(&self) -> f64 {
+        if self.duration.as_millis() == 0 {
+            return 0.0;
+        }
         (self.size as f64 * 8.0) / (self.duration.as_millis() as f64 / (1000.0))
     }
+
+    /// bit/s over the samples after the warm-up, or `None` without a warm-up or
+    /// when the test ended before the warm-up did.
+    pub fn steady_state_bps(&self) -> Option<f64> {
+        if self.warm_up.is_zero() || self.sample_interval.is_zero() {
+            return None;
+        }
+        let skipped = self
+            .warm_up
+            .as_nanos()
+            .div_ceil(self.sample_interval.as_nanos()) as usize;
+        let steady_samples = self.samples.get(skipped..)?;
+        let steady_duration = self
+            .duration
+            .checked_sub(self.sample_interval * skipped as u32)
+            .filter(|duration| !duration.is_zero())?;
+        Some(bits_per_second(
+            steady_samples.iter().sum(),
+            steady_duration,
+        ))
+    }
+
+    /// bit/s of each complete sample interval after the warm-up.
+    pub fn interval_bps(&self) -> Vec<f64> {
+        if self.sample_interval.is_zero() {
+            return vec![];
+        }
+        let interval = self.sample_interval.as_nanos();
+        let skipped = self.warm_up.as_nanos().div_ceil(interval) as usize;
+        // The last interval is cut short by the end of the test.
+        let complete = (self.duration.as_nanos() / interval) as usize;
+        self.samples
+            .iter()
+            .take(complete)
+            .skip(skipped)
+            .map(|&bytes| bits_per_second(bytes, self.sample_interval))
+            .collect()
+    }
+}
+
+/// Snapshot of a running download or upload test, passed to progress callbacks.
+#[derive(Clone, Debug, Default)]
+pub struct SpeedTestProgress {
+    /// Bytes transferred since the start of the test
+    pub bytes: usize,
+    pub elapsed: Duration,
+    /// bit/s since the previous report
+    pub instantaneous_bps: f64,
+    /// bit/s since the start of the test
+    pub average_bps: f64,
+    pub active_connections: usize,
+}
+
+/// Minimum time between progress reports that only carry new bytes.
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Length of the intervals in [`SpeedMeasurement::samples`].
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keeps the running totals of a test shared by all of its connections and reports
+/// them to the progress callback.
+pub(crate) struct TransferTracker<F> {
+    start_time: Instant,
+    bytes: AtomicUsize,
+    active_connections: AtomicUsize,
+    // Elapsed time and bytes as of the previous report.
+    last_report: Mutex<(Duration, usize)>,
+    // Bytes per `SAMPLE_INTERVAL` since `start_time`.
+    samples: Mutex<Vec<usize>>,
+    progress_callback: F,
+}
+
+impl<F> TransferTracker<F>
+where
+    F: Fn(&SpeedTestProgress),
+{
+    pub(crate) fn new(progress_callback: F) -> TransferTracker<F> {
+        TransferTracker {
+            start_time: Instant::now(),
+            bytes: AtomicUsize::new(0),
+            active_connections: AtomicUsize::new(0),
+            last_report: Mutex::new((Duration::ZERO, 0)),
+            samples: Mutex::new(vec![]),
+            progress_callback,
+        }
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.report(true);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        self.report(true);
+    }
+
+    pub(crate) fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if bytes > 0 {
+            let index = sample_index(self.start_time.elapsed());
+            let mut samples = self.samples.lock().unwrap();
+            if samples.len() <= index {
+                samples.resize(index + 1, 0);
+            }
+            samples[index] += bytes;
+        }
+        self.report(false);
+    }
+
+    /// Bytes per sample interval, padded with empty intervals up to `duration`.
+    pub(crate) fn samples(&self, duration: Duration) -> Vec<usize> {
+        let mut samples = self.samples.lock().unwrap().clone();
+        let count = sample_index(duration.saturating_sub(Duration::from_nanos(1))) + 1;
+        if samples.len() < count {
+            samples.resize(count, 0);
+        }
+        samples
+    }
+
+    fn report(&self, force: bool) {
+        let elapsed = self.start_time.elapsed();
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let (last_elapsed, last_bytes) = {
+            let mut last_report = if force {
+                self.last_report.lock().unwrap()
+            } else {
+                // Another connection is reporting right now, so this one can be skipped.
+                let Ok(last_report) = self.last_report.try_lock() else {
+                    return;
+                };
+                last_report
+            };
+            if !force && elapsed.saturating_sub(last_report.0) < PROGRESS_REPORT_INTERVAL {
+                return;
+            }
+            std::mem::replace(&mut *last_report, (elapsed, bytes))
+        };
+        (self.progress_callback)(&SpeedTestProgress {
+            bytes,
+            elapsed,
+            instantaneous_bps: bits_per_second(
+                bytes.saturating_sub(last_bytes),
+                elapsed.saturating_sub(last_elapsed),
+            ),
+            average_bps: bits_per_second(bytes, elapsed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+        });
+    }
+}
+
+fn sample_index(elapsed: Duration) -> usize {
+    (elapsed.as_nanos() / SAMPLE_INTERVAL.as_nanos()) as usize
+}
+
+fn bits_per_second(bytes: usize, duration: Duration) -> f64 {
+    if duration.is_zero() {
+        return 0.0;
+    }
+    (bytes as f64 * 8.0) / duration.as_secs_f64()
 }
 
+/// Runs the download test. When cancelled, the measurement covers what was
+/// downloaded up to that point.
 pub fn test_download_with_progress_and_config<F>(
     server: &SpeedTestServer,
     progress_callback: F,
     config: &mut SpeedTestConfig,
+    cancellation_token: &CancellationToken,
 ) -> Result<SpeedMeasurement, SpeedTestError>
 where
-    F: Fn() + Send + Sync + 'static,
+    F: Fn(&SpeedTestProgress) + Send + Sync + 'static,
 {
     info!("Testing Download speed");
-    let root_url = Url::parse(&server.url)?;
-
-    let mut urls = vec![];
-    for size in &config.sizes.download {
-        let mut download_with_size_url = root_url.clone();
-        {
-            let mut path_segments_mut = download_with_size_url
-                .path_segments_mut()
-                .map_err(|_| SpeedTestError::ServerParseError)?;
-            path_segments_mut.push(&format!("random{size}x{size}.jpg"));
-        }
-        for _ in 0..config.counts.download {
-            urls.push(download_with_size_url.clone());
-        }
-    }
+    let urls = download_urls(server, config)?;
 
     let _request_count = urls.len();
 
@@ -236,11 +855,9 @@ where
         })
         .collect::<Result<Vec<_>, SpeedTestError>>()?;
 
-    // TODO: Setup Ctrl-C Termination to use this "event".
-    let early_termination = AtomicBool::new(false);
-
     // Start Timer
     let start_time = SystemTime::now();
+    let tracker = TransferTracker::new(progress_callback);
 
     info!("Download Threads: {}", config.threads.download);
     let pool = rayon::ThreadPoolBuilder::new()
@@ -255,24 +872,30 @@ where
             // Make it sequential like the original. Ramp up the file sizes.
             .par_bridge()
             .map(|r| {
+                if (SystemTime::now().duration_since(start_time)? >= config.length.download)
+                    || cancellation_token.is_cancelled()
+                {
+                    return Ok(0);
+                }
                 let client = Client::new();
                 // let downloaded_count = vec![];
-                progress_callback();
                 info!("Requesting {}", r.url());
-                let mut response = client.execute(r)?;
+                let mut response = client.execute(r)?.error_for_status()?;
+                tracker.connection_opened();
                 let mut buf = [0u8; 10240];
                 let mut read_amounts = vec![];
-                while (SystemTime::now().duration_since(start_time)? < config.length.upload)
-                    && !early_termination.load(Ordering::Relaxed)
+                while (SystemTime::now().duration_since(start_time)? < config.length.download)
+                    && !cancellation_token.is_cancelled()
                 {
                     let read_amount = response.read(&mut buf)?;
                     read_amounts.push(read_amount);
+                    tracker.add(read_amount);
                     if read_amount == 0 {
                         break;
                     }
                 }
                 let total_transfered = read_amounts.iter().sum::<usize>();
-                progress_callback();
+                tracker.connection_closed();
 
                 Ok(total_transfered)
             })
@@ -282,55 +905,135 @@ where
     let total_transferred: usize = total_transferred_per_thread?.iter().sum();
 
     let end_time = SystemTime::now();
+    let duration = end_time.duration_since(start_time)?;
 
-    let measurement = SpeedMeasurement {
+    let mut measurement = SpeedMeasurement {
         size: total_transferred,
-        duration: end_time.duration_since(start_time)?,
+        duration,
+        requests: vec![],
+        sample_interval: SAMPLE_INTERVAL,
+        samples: tracker.samples(duration),
+        warm_up: config.length.warm_up,
+        statistics: None,
     };
+    measurement.statistics = Statistics::from_values(&measurement.interval_bps());
 
-    if measurement.bps_f64() > 100000.0 {
-        config.threads.upload = 8
-    }
+    adjust_upload_threads(config, &measurement);
 
     Ok(measurement)
 }
 
+pub(crate) fn download_urls(
+    server: &SpeedTestServer,
+    config: &SpeedTestConfig,
+) -> Result<Vec<Url>, SpeedTestError> {
+    let root_url = Url::parse(&server.url)?;
+
+    let mut urls = vec![];
+    for size in &config.sizes.download {
+        let mut download_with_size_url = root_url.clone();
+        {
+            let mut path_segments_mut = download_with_size_url
+                .path_segments_mut()
+                .map_err(|_| SpeedTestError::ServerParseError)?;
+            path_segments_mut.push(&format!("random{size}x{size}.jpg"));
+        }
+        for _ in 0..config.counts.download {
+            urls.push(download_with_size_url.clone());
+        }
+    }
+    Ok(urls)
+}
+
+pub(crate) fn adjust_upload_threads(config: &mut SpeedTestConfig, download: &SpeedMeasurement) {
+    if download.bps_f64() > 100000.0 {
+        config.threads.upload = 8
+    }
+}
+
 #[derive(Debug)]
 pub struct SpeedTestUploadRequest {
     pub request: Request,
     pub size: usize,
+    pub sent: Arc<AtomicUsize>,
 }
 
+/// Upload body that counts the bytes read out of it, i.e. handed to the connection,
+/// and cuts the request off once the upload test length is reached or the test is
+/// cancelled.
+struct CountingRead<R, F> {
+    inner: R,
+    sent: Arc<AtomicUsize>,
+    tracker: Arc<TransferTracker<F>>,
+    start_time: SystemTime,
+    length: Duration,
+    cancellation_token: CancellationToken,
+}
+
+impl<R, F> Read for CountingRead<R, F>
+where
+    R: Read,
+    F: Fn(&SpeedTestProgress),
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let elapsed = SystemTime::now()
+            .duration_since(self.start_time)
+            .map_err(io::Error::other)?;
+        if elapsed >= self.length {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "upload test length reached",
+            ));
+        }
+        if self.cancellation_token.is_cancelled() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "upload test cancelled",
+            ));
+        }
+        let read_amount = self.inner.read(buf)?;
+        self.sent.fetch_add(read_amount, Ordering::Relaxed);
+        self.tracker.add(read_amount);
+        Ok(read_amount)
+    }
+}
+
+/// Runs the upload test. When cancelled, the measurement covers what was
+/// uploaded up to that point.
 pub fn test_upload_with_progress_and_config<F>(
     server: &SpeedTestServer,
     progress_callback: F,
     config: &SpeedTestConfig,
+    cancellation_token: &CancellationToken,
 ) -> Result<SpeedMeasurement, SpeedTestError>
 where
-    F: Fn() + Send + Sync + 'static,
+    F: Fn(&SpeedTestProgress) + Send + Sync + 'static,
 {
     info!("Testing Upload speed");
 
-    let mut sizes = vec![];
-    for &size in &config.sizes.upload {
-        for _ in 0..config.counts.upload {
-            sizes.push(size)
-        }
-    }
+    let sizes = upload_sizes(config);
 
     let best_url = Url::parse(&server.url)?;
 
     let request_count = config.upload_max;
 
+    // Start Timer
+    let start_time = SystemTime::now();
+    let tracker = Arc::new(TransferTracker::new(progress_callback));
+
     let requests = sizes
         .into_iter()
         .map(|size| {
-            let content_iter = b"content1="
-                .iter()
-                .chain(b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ".iter().cycle())
-                .take(size);
-            let content_iter_read = iter_read::IterRead::new(content_iter);
-            let body = Body::sized(content_iter_read, size as u64);
+            let sent = Arc::new(AtomicUsize::new(0));
+            let content_read = CountingRead {
+                inner: iter_read::IterRead::new(upload_content(size)),
+                sent: sent.clone(),
+                tracker: tracker.clone(),
+                start_time,
+                length: config.length.upload,
+                cancellation_token: cancellation_token.clone(),
+            };
+            let body = Body::sized(content_read, size as u64);
             let mut request = Request::new(reqwest::Method::POST, best_url.clone());
             request.headers_mut().insert(
                 reqwest::header::USER_AGENT,
@@ -341,14 +1044,13 @@ where
                 HeaderValue::from_static("close"),
             );
             *request.body_mut() = Some(body);
-            Ok(SpeedTestUploadRequest { request, size })
+            Ok(SpeedTestUploadRequest {
+                request,
+                size,
+                sent,
+            })
         })
         .collect::<Result<Vec<_>, SpeedTestError>>()?;
-    // TODO: Setup Ctrl-C Termination to use this "event".
-    let early_termination = AtomicBool::new(false);
-
-    // Start Timer
-    let start_time = SystemTime::now();
 
     info!("Upload Threads: {}", config.threads.upload);
     let pool = rayon::ThreadPoolBuilder::new()
@@ -356,61 +1058,173 @@ where
         .build()?;
 
     info!("Total to be requested {:?}", requests.len());
-    let total_transferred_per_thread = pool.install(|| {
+    let request_measurements = pool.install(|| {
         requests
             .into_iter()
             .take(request_count)
             // Make it sequential like the original. Ramp up the file sizes.
             .par_bridge()
             .map(|r| {
-                progress_callback();
-
-                if (SystemTime::now().duration_since(start_time)? < config.length.upload)
-                    && !early_termination.load(Ordering::Relaxed)
+                if (SystemTime::now().duration_since(start_time)? >= config.length.upload)
+                    || cancellation_token.is_cancelled()
                 {
-                    let client = Client::new();
-                    info!("Requesting {}", r.request.url());
-                    let response = client.execute(r.request);
-                    if response.is_err() {
-                        return Ok(r.size);
-                    };
-                } else {
-                    return Ok(0);
+                    return Ok(None);
                 }
-                progress_callback();
 
-                Ok(r.size)
+                let request_start_time = SystemTime::now();
+                let client = Client::new();
+                info!("Requesting {}", r.request.url());
+                tracker.connection_opened();
+                let response = client.execute(r.request);
+                tracker.connection_closed();
+                let error = match response {
+                    Ok(response) => response.error_for_status().err().map(|err| {
+                        info!("Upload request rejected: {err:?}");
+                        err.to_string()
+                    }),
+                    // Requests cut off by the end of the test still sent what they sent.
+                    Err(_)
+                        if SystemTime::now().duration_since(start_time)?
+                            >= config.length.upload
+                            || cancellation_token.is_cancelled() =>
+                    {
+                        None
+                    }
+                    Err(err) => {
+                        info!("Upload request failed: {err:?}");
+                        Some(err.to_string())
+                    }
+                };
+
+                Ok(Some(SpeedTestRequestMeasurement {
+                    size: r.size,
+                    sent: r.sent.load(Ordering::Relaxed),
+                    duration: SystemTime::now().duration_since(request_start_time)?,
+                    error,
+                }))
             })
             .collect::<Result<Vec<_>, SpeedTestError>>()
     });
 
-    let total_transferred: usize = total_transferred_per_thread?.iter().sum();
+    let requests: Vec<_> = request_measurements?.into_iter().flatten().collect();
+    check_upload_requests(&requests)?;
+
+    let total_transferred: usize = requests
+        .iter()
+        .filter(|r| r.error.is_none())
+        .map(|r| r.sent)
+        .sum();
 
     let end_time = SystemTime::now();
+    let duration = end_time.duration_since(start_time)?;
 
-    let measurement = SpeedMeasurement {
+    let mut measurement = SpeedMeasurement {
         size: total_transferred,
-        duration: end_time.duration_since(start_time)?,
+        duration,
+        requests,
+        sample_interval: SAMPLE_INTERVAL,
+        samples: tracker.samples(duration),
+        warm_up: config.length.warm_up,
+        statistics: None,
     };
+    measurement.statistics = Statistics::from_values(&measurement.interval_bps());
 
     Ok(measurement)
 }
 
-#[derive(Debug)]
-pub struct SpeedTestResult<'a, 'b, 'c> {
-    pub download_measurement: Option<&'a SpeedMeasurement>,
-    pub upload_measurement: Option<&'b SpeedMeasurement>,
-    pub server: &'c SpeedTestServer,
-    pub latency_measurement: &'c SpeedTestLatencyTestResult<'c>,
+/// Fail the upload test if not a single request went through, e.g. because the server
+/// rejects uploads, so that the session can fall back to another server.
+pub(crate) fn check_upload_requests(
+    requests: &[SpeedTestRequestMeasurement],
+) -> Result<(), SpeedTestError> {
+    match requests.first() {
+        Some(first) if requests.iter().all(|r| r.error.is_some()) => Err(
+            SpeedTestError::UploadFailed(first.error.clone().unwrap_or_default()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn upload_sizes(config: &SpeedTestConfig) -> Vec<usize> {
+    let mut sizes = vec![];
+    for &size in &config.sizes.upload {
+        for _ in 0..config.counts.upload {
+            sizes.push(size)
+        }
+    }
+    sizes
+}
+
+pub(crate) fn upload_content(size: usize) -> impl Iterator<Item = &'static u8> {
+    b"content1="
+        .iter()
+        .chain(b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ".iter().cycle())
+        .take(size)
+}
+
+/// Phase of a speed test run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeedTestPhase {
+    Download,
+    Upload,
 }
 
-impl<'a, 'b, 'c> SpeedTestResult<'a, 'b, 'c> {
+/// A server that failed during a phase, after which the next server in the latency
+/// ranking was tried.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpeedTestServerAttempt {
+    pub server: SpeedTestServer,
+    pub phase: SpeedTestPhase,
+    pub error: String,
+}
+
+/// Everything measured in a single run, suitable for storing and comparing runs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpeedTestResult {
+    pub download_measurement: Option<SpeedMeasurement>,
+    pub upload_measurement: Option<SpeedMeasurement>,
+    pub server: SpeedTestServer,
+    pub latency_measurement: SpeedTestLatencyTestResult,
+    pub client: SpeedTestClientConfig,
+    /// When the run started.
+    pub timestamp: DateTime<Utc>,
+    /// Result image URL, if the result has been shared.
+    pub share_url: Option<String>,
+    /// Latency sampled while the download test ran, if enabled.
+    #[serde(default)]
+    pub download_loaded_latency: Option<SpeedTestLatencyTestResult>,
+    /// Latency sampled while the upload test ran, if enabled.
+    #[serde(default)]
+    pub upload_loaded_latency: Option<SpeedTestLatencyTestResult>,
+    /// Servers that failed during a phase, in the order they were tried.
+    #[serde(default)]
+    pub failed_attempts: Vec<SpeedTestServerAttempt>,
+    /// Server the download was measured on. Differs from `server` only if the download
+    /// fell back to another server after `server` completed an earlier phase.
+    #[serde(default)]
+    pub download_server: Option<SpeedTestServer>,
+    /// Server the upload was measured on, see `download_server`.
+    #[serde(default)]
+    pub upload_server: Option<SpeedTestServer>,
+}
+
+impl SpeedTestResult {
+    /// Worse of the download and upload bufferbloat grades, if loaded latency was measured.
+    pub fn bufferbloat_grade(&self) -> Option<BufferbloatGrade> {
+        [&self.download_loaded_latency, &self.upload_loaded_latency]
+            .into_iter()
+            .flatten()
+            .filter_map(|loaded| BufferbloatGrade::grade(&self.latency_measurement, loaded))
+            .max()
+    }
+
     pub fn hash(&self) -> String {
         let hashed_str = format!(
             "{}-{}-{}-{}",
             self.latency_measurement.latency.as_millis(),
-            self.upload_measurement.map_or(0, |x| x.kbps()),
-            self.download_measurement.map_or(0, |x| x.kbps()),
+            self.upload_measurement.as_ref().map_or(0, |x| x.kbps()),
+            self.download_measurement.as_ref().map_or(0, |x| x.kbps()),
             "297aae72"
         );
 
@@ -418,14 +1232,21 @@ impl<'a, 'b, 'c> SpeedTestResult<'a, 'b, 'c> {
     }
 }
 
-pub fn get_share_url(speedtest_result: &SpeedTestResult) -> Result<String, SpeedTestError> {
+pub fn get_share_url(
+    endpoints: &SpeedTestEndpoints,
+    speedtest_result: &SpeedTestResult,
+) -> Result<String, SpeedTestError> {
     info!("Generating share URL");
 
     let download = speedtest_result
         .download_measurement
+        .as_ref()
         .map_or(0, |x| x.kbps());
     info!("Download parameter is {download:?}");
-    let upload = speedtest_result.upload_measurement.map_or(0, |x| x.kbps());
+    let upload = speedtest_result
+        .upload_measurement
+        .as_ref()
+        .map_or(0, |x| x.kbps());
     info!("Upload parameter is {upload:?}");
     let server = speedtest_result.server.id;
     info!("Server parameter is {server:?}");
@@ -452,7 +1273,7 @@ pub fn get_share_url(speedtest_result: &SpeedTestResult) -> Result<String, Speed
 
     let client = Client::new();
     let res = client
-        .post("http://www.speedtest.net/api/api.php")
+        .post(&endpoints.share)
         .header(CONNECTION, "close")
         .header(REFERER, "http://c.speedtest.net/flash/speedtest.swf")
         .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
@@ -460,7 +1281,7 @@ pub fn get_share_url(speedtest_result: &SpeedTestResult) -> Result<String, Speed
         .send();
     let encode_return = res?.text()?;
     let response_id = parse_share_request_response_id(encode_return.as_bytes())?;
-    Ok(format!("http://www.speedtest.net/result/{response_id}.png"))
+    Ok(format!("{}/{response_id}.png", endpoints.share_result))
 }
 
 pub fn parse_share_request_response_id(input: &[u8]) -> Result<String, SpeedTestError> {
@@ -475,6 +1296,7 @@ pub fn parse_share_request_response_id(input: &[u8]) -> Result<String, SpeedTest
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{sample_server, small_transfer_config, unreachable_url};
 
     #[test]
     fn test_parse_share_request_response_id() {
@@ -487,15 +1309,18 @@ mod tests {
         let download_measurement = SpeedMeasurement {
             size: (6096 * 100) as usize,
             duration: Duration::new(1, 0),
+            ..SpeedMeasurement::default()
         };
         println!("Download: {:?}", download_measurement);
         let upload_measurement = SpeedMeasurement {
             size: (1861 * 100) as usize,
             duration: Duration::new(1, 0),
+            ..SpeedMeasurement::default()
         };
         println!("Upload: {:?}", upload_measurement);
         let server = SpeedTestServer {
             country: "".to_owned(),
+            country_code: "".to_owned(),
             host: "".to_owned(),
             id: 5116,
             location: EarthLocation {
@@ -509,17 +1334,33 @@ mod tests {
         };
         println!("Server: {server:?}");
         let latency_measurement = SpeedTestLatencyTestResult {
-            server: &server,
+            server: server.clone(),
             latency: Duration::from_millis(26),
+            samples: vec![Duration::from_millis(26)],
+            failed_samples: 0,
+            error: None,
         };
         println!("Latency: {latency_measurement:?}");
         let request = SpeedTestResult {
-            download_measurement: Some(&download_measurement),
-            upload_measurement: Some(&upload_measurement),
-            server: &server,
-            latency_measurement: &latency_measurement,
+            download_measurement: Some(download_measurement),
+            upload_measurement: Some(upload_measurement),
+            server,
+            latency_measurement,
+            client: SpeedTestClientConfig::default(),
+            timestamp: Utc::now(),
+            share_url: None,
+            download_loaded_latency: None,
+            upload_loaded_latency: None,
+            failed_attempts: vec![],
+            download_server: None,
+            upload_server: None,
         };
         assert_eq!(request.hash(), "f10eb3dd8d3c38a221e823d859680045");
+
+        let serialized = serde_json::to_string(&request).unwrap();
+        let deserialized: SpeedTestResult = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.hash(), "f10eb3dd8d3c38a221e823d859680045");
+        assert_eq!(deserialized.timestamp, request.timestamp);
     }
 
     #[test]
@@ -532,9 +1373,12 @@ mod tests {
         let _m = server
             .mock("GET", "/speedtest-config.php")
             .with_status(200)
-            .with_body_from_file("tests/config/stripped-config.php.xml")
+            .with_body_from_file("tests/config/config.php.xml")
             .create();
-        let _config = get_configuration();
+
+        let endpoints = SpeedTestEndpoints::with_base_url(&server.url());
+        let config = get_configuration(&endpoints, None).unwrap();
+        assert_eq!("Cox Communications", config.client.isp);
     }
 
     #[test]
@@ -542,11 +1386,526 @@ mod tests {
         let mut server = mockito::Server::new();
 
         let _m = server
-            .mock("GET", "/speedtest-config.php")
+            .mock("GET", "/speedtest-servers.php")
             .with_status(200)
             .with_body_from_file("tests/config/servers-static.php.xml")
             .create();
 
-        let _server_list_config = get_server_list_with_config(&SpeedTestConfig::default());
+        let endpoints = SpeedTestEndpoints::with_base_url(&server.url());
+        let server_list_config =
+            get_server_list_with_config(&endpoints, None, &SpeedTestConfig::default()).unwrap();
+        assert!(!server_list_config.servers.is_empty());
+    }
+
+    #[test]
+    fn test_get_server_list_with_config_json() {
+        let mut server = mockito::Server::new();
+
+        let _m = server
+            .mock("GET", "/speedtest-servers.php")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body_from_file("tests/config/servers.json")
+            .create();
+
+        let endpoints = SpeedTestEndpoints::with_base_url(&server.url());
+        let server_list_config =
+            get_server_list_with_config(&endpoints, None, &SpeedTestConfig::default()).unwrap();
+        assert_eq!(3, server_list_config.servers.len());
+    }
+
+    #[test]
+    fn test_get_configuration_and_server_list_from_cache() {
+        let mut server = mockito::Server::new();
+
+        let config_mock = server
+            .mock("GET", "/speedtest-config.php")
+            .with_status(200)
+            .with_body_from_file("tests/config/config.php.xml")
+            .expect(1)
+            .create();
+        let servers_mock = server
+            .mock("GET", "/servers.json")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body_from_file("tests/config/servers.json")
+            .expect(1)
+            .create();
+
+        let cache_dir = std::env::temp_dir().join(format!(
+            "speedtest-rs-endpoints-cache-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let endpoints = SpeedTestEndpoints {
+            server_lists: vec![format!("{}/servers.json", server.url())],
+            ..SpeedTestEndpoints::with_base_url(&server.url())
+        };
+        let cache = SpeedTestCache::new(&cache_dir);
+        for _ in 0..2 {
+            let config = get_configuration(&endpoints, Some(&cache)).unwrap();
+            let server_list =
+                get_server_list_with_config(&endpoints, Some(&cache), &config).unwrap();
+            assert_eq!(3, server_list.servers.len());
+        }
+        config_mock.assert();
+        servers_mock.assert();
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_configuration_and_server_list_from_file() {
+        let config = get_configuration_from_file(Path::new("tests/config/config.php.xml")).unwrap();
+        assert_eq!("Cox Communications", config.client.isp);
+
+        let xml_list = get_server_list_from_file(
+            Path::new("tests/config/stripped-servers-static.php.xml"),
+            &config,
+        )
+        .unwrap();
+        assert!(!xml_list.servers.is_empty());
+        let json_list =
+            get_server_list_from_file(Path::new("tests/config/servers.json"), &config).unwrap();
+        assert_eq!(3, json_list.servers.len());
+
+        assert!(matches!(
+            get_configuration_from_file(Path::new("tests/config/missing.xml")),
+            Err(SpeedTestError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_server_list_merges_sources() {
+        let mut server = mockito::Server::new();
+
+        let _failing = server.mock("GET", "/failing.php").with_status(500).create();
+        let _json = server
+            .mock("GET", "/servers.json")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body_from_file("tests/config/servers.json")
+            .create();
+        let _xml = server
+            .mock("GET", "/servers.xml")
+            .with_status(200)
+            .with_body_from_file("tests/config/stripped-servers-static.php.xml")
+            .create();
+
+        let endpoints = SpeedTestEndpoints {
+            server_lists: ["failing.php", "servers.json", "servers.xml"]
+                .iter()
+                .map(|path| format!("{}/{path}", server.url()))
+                .collect(),
+            ..SpeedTestEndpoints::with_base_url(&server.url())
+        };
+        let servers = get_server_list_with_config(&endpoints, None, &SpeedTestConfig::default())
+            .unwrap()
+            .servers;
+        let ids: Vec<u32> = servers.iter().map(|s| s.id).collect();
+        assert_eq!(vec![5827, 5256, 4961], ids[..3]);
+        // The XML list adds the servers missing from the JSON list, e.g. 4600, while the
+        // servers in both lists appear once.
+        assert!(ids.contains(&4600));
+        assert_eq!(12, ids.len());
+        let mut unique_ids = ids.clone();
+        unique_ids.sort_unstable();
+        unique_ids.dedup();
+        assert_eq!(ids.len(), unique_ids.len());
+
+        let endpoints = SpeedTestEndpoints {
+            server_lists: vec![format!("{}/failing.php", server.url())],
+            ..SpeedTestEndpoints::with_base_url(&server.url())
+        };
+        assert!(matches!(
+            get_server_list_with_config(&endpoints, None, &SpeedTestConfig::default()),
+            Err(SpeedTestError::Reqwest(_))
+        ));
+    }
+
+    #[test]
+    fn test_download_honours_download_length() {
+        let mut server = mockito::Server::new();
+
+        // Trickle the body out for far longer than the download length.
+        let m = server
+            .mock(
+                "GET",
+                mockito::Matcher::Regex("random350x350.jpg".to_owned()),
+            )
+            .with_status(200)
+            .with_chunked_body(|w| {
+                for _ in 0..100 {
+                    w.write_all(&[0u8; 1024])?;
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Ok(())
+            })
+            .expect(1)
+            .create();
+
+        let mut config = small_transfer_config();
+        config.counts.download = 4;
+        config.length.set_download(Duration::from_millis(500));
+        let measurement = test_download_with_progress_and_config(
+            &sample_server(&server.url()),
+            |_| {},
+            &mut config,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert!(measurement.duration < Duration::from_secs(2));
+        assert!(measurement.size > 0);
+        assert!(measurement.size < 100 * 1024);
+        // The trickled body is spread over several sample intervals.
+        assert_eq!(SAMPLE_INTERVAL, measurement.sample_interval);
+        assert_eq!(measurement.size, measurement.samples.iter().sum::<usize>());
+        assert!(
+            measurement
+                .samples
+                .iter()
+                .filter(|&&bytes| bytes > 0)
+                .count()
+                > 1
+        );
+        assert!(measurement.statistics.is_some());
+        // The requests queued behind the first are not made after the download length.
+        m.assert();
+    }
+
+    #[test]
+    fn test_upload_honours_upload_length() {
+        let mut server = mockito::Server::new();
+
+        let _m = server
+            .mock("POST", "/speedtest/upload.php")
+            .with_status(200)
+            .with_chunked_body(|w| {
+                std::thread::sleep(Duration::from_millis(250));
+                w.write_all(b"size=32768")
+            })
+            .create();
+
+        let mut config = small_transfer_config();
+        // The download length must not leak into the upload test.
+        config.length.set_download(Duration::from_secs(30));
+        config.length.set_upload(Duration::from_millis(500));
+        let measurement = test_upload_with_progress_and_config(
+            &sample_server(&server.url()),
+            |_| {},
+            &config,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert!(measurement.duration < Duration::from_secs(2));
+        assert!(measurement.size > 0);
+        assert!(measurement.size < 20 * 32768);
+    }
+
+    #[test]
+    fn test_speed_of_an_instant_test_is_zero() {
+        let measurement = SpeedMeasurement {
+            size: 32768,
+            duration: Duration::from_micros(500),
+            ..SpeedMeasurement::default()
+        };
+        assert_eq!(0, measurement.kbps());
+        assert_eq!(0.0, measurement.bps_f64());
+    }
+
+    #[test]
+    fn test_steady_state_bps_skips_warm_up() {
+        let mut measurement = SpeedMeasurement {
+            size: 1_000 + 9 * 10_000,
+            duration: Duration::from_secs(1),
+            sample_interval: SAMPLE_INTERVAL,
+            samples: [vec![1_000], vec![10_000; 9]].concat(),
+            ..SpeedMeasurement::default()
+        };
+        assert_eq!(None, measurement.steady_state_bps());
+
+        measurement.warm_up = Duration::from_millis(50);
+        assert_eq!(Some(800_000.0), measurement.steady_state_bps());
+        assert!(measurement.bps_f64() < 800_000.0);
+        assert_eq!(vec![800_000.0; 9], measurement.interval_bps());
+
+        measurement.warm_up = Duration::from_secs(2);
+        assert_eq!(None, measurement.steady_state_bps());
+    }
+
+    #[test]
+    fn test_upload_excludes_failed_requests() {
+        let mut server = mockito::Server::new();
+
+        let _m = server
+            .mock("POST", "/speedtest/upload.php")
+            .with_status(200)
+            .create();
+
+        let mut config = small_transfer_config();
+        config.sizes.upload = vec![32768, 65536];
+        config.counts.upload = 1;
+        config.upload_max = 2;
+        let measurement = test_upload_with_progress_and_config(
+            &sample_server(&server.url()),
+            |_| {},
+            &config,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(2, measurement.requests.len());
+        assert!(measurement.requests.iter().all(|r| r.sent == r.size));
+        assert_eq!(32768 + 65536, measurement.size);
+
+        let result = test_upload_with_progress_and_config(
+            &sample_server(&unreachable_url()),
+            |_| {},
+            &config,
+            &CancellationToken::new(),
+        );
+        assert!(matches!(result, Err(SpeedTestError::UploadFailed(_))));
+    }
+
+    #[test]
+    fn test_best_server_keeps_latency_samples() {
+        let mut server = mockito::Server::new();
+
+        let _m = server
+            .mock("GET", "/speedtest/latency.txt")
+            .with_status(200)
+            .with_body("test=test")
+            .expect(5)
+            .create();
+
+        let servers = [
+            sample_server(&unreachable_url()),
+            sample_server(&server.url()),
+        ];
+        let result =
+            get_best_server_based_on_latency(&servers, 5, &CancellationToken::new()).unwrap();
+        assert_eq!(servers[1].url, result.server.url);
+        assert_eq!(5, result.samples.len());
+        assert_eq!(0, result.failed_samples);
+        assert_eq!(0.0, result.loss_percent());
+        assert!(result.min() <= result.median());
+        assert!(result.median() <= result.max());
+    }
+
+    #[test]
+    fn test_rank_servers_by_latency_times_out_slow_servers() {
+        let mut fast = mockito::Server::new();
+        let mut slow = mockito::Server::new();
+
+        let _fast = fast
+            .mock("GET", "/speedtest/latency.txt")
+            .with_status(200)
+            .with_body("test=test")
+            .create();
+        let _slow = slow
+            .mock("GET", "/speedtest/latency.txt")
+            .with_status(200)
+            .with_chunked_body(|w| {
+                std::thread::sleep(Duration::from_secs(2));
+                w.write_all(b"test=test")
+            })
+            .create();
+
+        let options = SpeedTestLatencyOptions {
+            samples: 2,
+            server_timeout: Duration::from_millis(300),
+            deadline: Duration::from_secs(1),
+        };
+        let start_time = Instant::now();
+        let ranking = rank_servers_by_latency(
+            &[sample_server(&slow.url()), sample_server(&fast.url())],
+            &options,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+        assert_eq!(2, ranking.len());
+        assert_eq!(sample_server(&fast.url()).url, ranking[0].server.url);
+        assert_eq!(2, ranking[0].samples.len());
+        assert!(ranking[0].error.is_none());
+        assert_eq!(sample_server(&slow.url()).url, ranking[1].server.url);
+        assert_eq!(2, ranking[1].failed_samples);
+        assert!(ranking[1].error.is_some());
+    }
+
+    #[test]
+    fn test_rank_servers_by_latency_probes_more_servers_than_workers() {
+        let mut server = mockito::Server::new();
+        let _latency = server
+            .mock("GET", "/speedtest/latency.txt")
+            .with_status(200)
+            .with_body("test=test")
+            .create();
+
+        let servers: Vec<_> = (0..MAX_CONCURRENT_LATENCY_PROBES as u32 * 2 + 1)
+            .map(|id| SpeedTestServer {
+                id,
+                ..sample_server(&server.url())
+            })
+            .collect();
+        let options = SpeedTestLatencyOptions {
+            samples: 1,
+            ..SpeedTestLatencyOptions::default()
+        };
+        let ranking =
+            rank_servers_by_latency(&servers, &options, &CancellationToken::new()).unwrap();
+        assert_eq!(servers.len(), ranking.len());
+        assert!(ranking.iter().all(|result| result.error.is_none()));
+    }
+
+    #[test]
+    fn test_rank_latency_results_prefers_fewer_lost_samples() {
+        let result = |id, latency, failed_samples| SpeedTestLatencyTestResult {
+            server: SpeedTestServer {
+                id,
+                ..sample_server("http://localhost")
+            },
+            latency: Duration::from_millis(latency),
+            samples: vec![Duration::from_millis(latency)],
+            failed_samples,
+            error: None,
+        };
+        let ranking = rank_latency_results(vec![
+            SpeedTestLatencyTestResult::from_error(sample_server("http://localhost"), 3, "".into()),
+            result(1, 10, 2),
+            result(2, 30, 0),
+            result(3, 20, 0),
+        ]);
+        let ids: Vec<u32> = ranking.iter().map(|result| result.server.id).collect();
+        assert_eq!(vec![3, 2, 1, sample_server("http://localhost").id], ids);
+    }
+
+    #[test]
+    fn test_latency_jitter_and_loss() {
+        let result = SpeedTestLatencyTestResult::from_round_trips(
+            sample_server("http://localhost"),
+            &[
+                Duration::from_millis(20),
+                Duration::from_millis(30),
+                Duration::from_millis(24),
+            ],
+            1,
+        );
+        assert_eq!(Duration::from_millis(10), result.min().unwrap());
+        assert_eq!(Duration::from_millis(15), result.max().unwrap());
+        assert_eq!(Duration::from_millis(12), result.median().unwrap());
+        assert_eq!(Duration::from_millis(4), result.jitter());
+        assert_eq!(25.0, result.loss_percent());
+    }
+
+    #[test]
+    fn test_cancelled_tests_stop() {
+        let mut server = mockito::Server::new();
+
+        let download_mock = server
+            .mock(
+                "GET",
+                mockito::Matcher::Regex("random350x350.jpg".to_owned()),
+            )
+            .with_status(200)
+            .expect(0)
+            .create();
+        let upload_mock = server
+            .mock("POST", "/speedtest/upload.php")
+            .with_status(200)
+            .expect(0)
+            .create();
+
+        let cancellation_token = CancellationToken::new();
+        cancellation_token.cancel();
+
+        let test_server = sample_server(&server.url());
+        assert!(matches!(
+            get_best_server_based_on_latency(
+                std::slice::from_ref(&test_server),
+                DEFAULT_LATENCY_SAMPLES,
+                &cancellation_token
+            ),
+            Err(SpeedTestError::Cancelled)
+        ));
+
+        let mut config = small_transfer_config();
+        let download_measurement = test_download_with_progress_and_config(
+            &test_server,
+            |_| {},
+            &mut config,
+            &cancellation_token,
+        )
+        .unwrap();
+        assert_eq!(0, download_measurement.size);
+
+        let upload_measurement = test_upload_with_progress_and_config(
+            &test_server,
+            |_| {},
+            &config,
+            &cancellation_token,
+        )
+        .unwrap();
+        assert_eq!(0, upload_measurement.size);
+
+        download_mock.assert();
+        upload_mock.assert();
+    }
+
+    #[test]
+    fn test_transfer_tracker_reports_progress() {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let tracker = {
+            let reports = reports.clone();
+            TransferTracker::new(move |progress: &SpeedTestProgress| {
+                reports.lock().unwrap().push(progress.clone())
+            })
+        };
+
+        tracker.connection_opened();
+        // Too soon after the previous report to be reported on its own.
+        tracker.add(1000);
+        std::thread::sleep(PROGRESS_REPORT_INTERVAL);
+        tracker.add(1000);
+        tracker.connection_closed();
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(3, reports.len());
+        assert_eq!(1, reports[0].active_connections);
+        assert_eq!(2000, reports[1].bytes);
+        assert!(reports[1].average_bps > 0.0);
+        assert!(reports[1].instantaneous_bps > 0.0);
+        assert_eq!(0, reports[2].active_connections);
+    }
+
+    #[test]
+    fn test_transfer_tracker_records_samples() {
+        let tracker = TransferTracker::new(|_: &SpeedTestProgress| {});
+
+        tracker.add(1000);
+        tracker.add(500);
+        std::thread::sleep(SAMPLE_INTERVAL * 2);
+        tracker.add(2000);
+
+        let samples = tracker.samples(SAMPLE_INTERVAL * 5);
+        assert_eq!(5, samples.len());
+        assert_eq!(1500, samples[0]);
+        assert_eq!(3500, samples.iter().sum::<usize>());
+        assert_eq!(0, samples[4]);
+    }
+
+    #[test]
+    fn test_endpoints_with_base_url() {
+        let endpoints = SpeedTestEndpoints::with_base_url("http://mirror.example.com/");
+        assert_eq!(
+            "http://mirror.example.com/speedtest-config.php",
+            endpoints.configuration
+        );
+        assert_eq!(
+            vec![
+                "http://mirror.example.com/speedtest-servers-static.php",
+                "http://mirror.example.com/speedtest-servers.php"
+            ],
+            endpoints.server_lists
+        );
+        assert_eq!("http://mirror.example.com/api/api.php", endpoints.share);
     }
 }
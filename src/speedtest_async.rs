@@ -0,0 +1,612 @@
+//! Non-blocking counterparts of the functions in [`crate::speedtest`] for use from an
+//! async runtime such as tokio. Parsing and measurement types are shared with the
+//! blocking path.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "log")]
+use log::info;
+
+#[cfg(not(feature = "log"))]
+use super::log::info;
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::header::{CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, USER_AGENT};
+use reqwest::{Body, Client, Response, Url};
+
+use crate::error::SpeedTestError;
+use crate::speedtest::{
+    self, CancellationToken, SpeedMeasurement, SpeedTestEndpoints, SpeedTestLatencyOptions,
+    SpeedTestLatencyTestResult, SpeedTestProgress, SpeedTestRequestMeasurement, SpeedTestServer,
+    TransferTracker, ST_USER_AGENT,
+};
+use crate::speedtest_cache::SpeedTestCache;
+use crate::speedtest_config::SpeedTestConfig;
+use crate::speedtest_servers_config::SpeedTestServersConfig;
+use crate::statistics::Statistics;
+
+pub async fn download_configuration(
+    endpoints: &SpeedTestEndpoints,
+) -> Result<Response, SpeedTestError> {
+    info!("Downloading Configuration from {}", endpoints.configuration);
+
+    let client = Client::new();
+    let res = client
+        .get(&endpoints.configuration)
+        .header(CONNECTION, "close")
+        .header(USER_AGENT, ST_USER_AGENT)
+        .send()
+        .await?;
+    info!("Downloaded Configuration from {}", endpoints.configuration);
+    Ok(res)
+}
+
+/// Download the configuration, or take it from `cache` while it is fresh.
+pub async fn get_configuration(
+    endpoints: &SpeedTestEndpoints,
+    cache: Option<&SpeedTestCache>,
+) -> Result<SpeedTestConfig, SpeedTestError> {
+    if let Some(spt_config) = speedtest::cached_configuration(endpoints, cache) {
+        return Ok(spt_config);
+    }
+    let config_body = download_configuration(endpoints).await?;
+    info!("Parsing Configuration");
+    let config_text = config_body.text().await?;
+    let spt_config = SpeedTestConfig::parse(&config_text)?;
+    info!("Parsed Configuration");
+    speedtest::cache_response(cache, &endpoints.configuration, None, &config_text);
+    Ok(spt_config)
+}
+
+pub async fn download_server_list(url: &str) -> Result<Response, SpeedTestError> {
+    info!("Download Server List from {url}");
+
+    let client = Client::new();
+    let server_res = client
+        .get(url)
+        .header(CONNECTION, "close")
+        .header(USER_AGENT, ST_USER_AGENT)
+        .send()
+        .await?
+        .error_for_status()?;
+    info!("Downloaded Server List");
+    Ok(server_res)
+}
+
+/// Download and merge the server lists of all sources in `endpoints`, taking those
+/// still fresh in `cache` from there. Fails only if none of them could be retrieved.
+pub async fn get_server_list_with_config(
+    endpoints: &SpeedTestEndpoints,
+    cache: Option<&SpeedTestCache>,
+    config: &SpeedTestConfig,
+) -> Result<SpeedTestServersConfig, SpeedTestError> {
+    let server_lists = stream::iter(&endpoints.server_lists)
+        .map(|url| get_server_list_from_url(url, cache, config))
+        .buffered(endpoints.server_lists.len().max(1))
+        .collect()
+        .await;
+    speedtest::merge_server_lists(server_lists)
+}
+
+pub async fn get_server_list_from_url(
+    url: &str,
+    cache: Option<&SpeedTestCache>,
+    config: &SpeedTestConfig,
+) -> Result<SpeedTestServersConfig, SpeedTestError> {
+    if let Some(server_list) = speedtest::cached_server_list(url, cache, config) {
+        return Ok(server_list);
+    }
+    let config_body = download_server_list(url).await?;
+    info!("Parsing Server List");
+    let content_type = config_body
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let server_config_string = config_body.text().await?;
+
+    let server_list = SpeedTestServersConfig::parse_with_content_type(
+        &server_config_string,
+        content_type.as_deref(),
+        config,
+    )?;
+    info!("Parsed Server List");
+    speedtest::cache_response(cache, url, content_type.as_deref(), &server_config_string);
+    Ok(server_list)
+}
+
+pub async fn get_best_server_based_on_latency(
+    servers: &[SpeedTestServer],
+    sample_count: usize,
+    cancellation_token: &CancellationToken,
+) -> Result<SpeedTestLatencyTestResult, SpeedTestError> {
+    let options = SpeedTestLatencyOptions {
+        samples: sample_count,
+        ..SpeedTestLatencyOptions::default()
+    };
+    let fastest = rank_servers_by_latency(servers, &options, cancellation_token)
+        .await?
+        .into_iter()
+        .find(|result| result.error.is_none());
+    info!("Fastest Server : {fastest:?}");
+    fastest.ok_or(SpeedTestError::LatencyTestClosestError)
+}
+
+pub async fn rank_servers_by_latency(
+    servers: &[SpeedTestServer],
+    options: &SpeedTestLatencyOptions,
+    cancellation_token: &CancellationToken,
+) -> Result<Vec<SpeedTestLatencyTestResult>, SpeedTestError> {
+    info!("Testing latency of {} servers", servers.len());
+    // Return error if no servers are available.
+    if servers.is_empty() {
+        return Err(SpeedTestError::LatencyTestNoServerError);
+    }
+    let client = Client::new();
+    let deadline = Instant::now() + options.deadline;
+    let results = stream::iter(servers)
+        .map(|server| probe_latency(&client, server, options, deadline, cancellation_token))
+        .buffer_unordered(speedtest::MAX_CONCURRENT_LATENCY_PROBES)
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(speedtest::rank_latency_results(results))
+}
+
+async fn probe_latency(
+    client: &Client,
+    server: &SpeedTestServer,
+    options: &SpeedTestLatencyOptions,
+    deadline: Instant,
+    cancellation_token: &CancellationToken,
+) -> Result<SpeedTestLatencyTestResult, SpeedTestError> {
+    let latency_path = match speedtest::latency_url(server) {
+        Ok(latency_path) => latency_path,
+        // The only way this fails is a server URL without a path to replace.
+        Err(_) => {
+            return Ok(SpeedTestLatencyTestResult::from_error(
+                server.clone(),
+                0,
+                format!("Invalid server URL {}", server.url),
+            ))
+        }
+    };
+    info!("Downloading: {:?}", latency_path);
+    let server_deadline = deadline.min(Instant::now() + options.server_timeout);
+    let mut latency_measurements = vec![];
+    let mut failed_samples = 0;
+    let mut last_error = None;
+    for _ in 0..options.samples.max(1) {
+        if cancellation_token.is_cancelled() {
+            return Err(SpeedTestError::Cancelled);
+        }
+        let start_time = Instant::now();
+        let Some(remaining) = speedtest::sample_timeout(server_deadline, start_time) else {
+            failed_samples += 1;
+            continue;
+        };
+        let res = match client
+            .get(&latency_path)
+            .header(CONNECTION, "close")
+            .header(USER_AGENT, ST_USER_AGENT)
+            .timeout(remaining)
+            .send()
+            .await
+        {
+            Ok(res) => res.bytes().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            info!("Error: {err:?}");
+            failed_samples += 1;
+            last_error = Some(err.to_string());
+            continue;
+        }
+        let latency_measurement = start_time.elapsed();
+        info!("Sampled {} ms", latency_measurement.as_millis());
+        latency_measurements.push(latency_measurement);
+    }
+    Ok(speedtest::latency_result(
+        server,
+        &latency_measurements,
+        failed_samples,
+        last_error,
+    ))
+}
+
+pub async fn test_download_with_progress_and_config<F>(
+    server: &SpeedTestServer,
+    progress_callback: F,
+    config: &mut SpeedTestConfig,
+    cancellation_token: &CancellationToken,
+) -> Result<SpeedMeasurement, SpeedTestError>
+where
+    F: Fn(&SpeedTestProgress) + Send + Sync,
+{
+    info!("Testing Download speed");
+    let urls = speedtest::download_urls(server, config)?;
+
+    let client = Client::new();
+    let length = config.length.download;
+
+    // Start Timer
+    let start_time = SystemTime::now();
+    let tracker = TransferTracker::new(progress_callback);
+
+    info!("Download Threads: {}", config.threads.download);
+    let total_transferred_per_request = stream::iter(urls)
+        .map(|url| {
+            let client = &client;
+            let tracker = &tracker;
+            async move {
+                if SystemTime::now().duration_since(start_time)? >= length
+                    || cancellation_token.is_cancelled()
+                {
+                    return Ok(0);
+                }
+                info!("Requesting {url}");
+                let mut response = client
+                    .get(url)
+                    .header(CACHE_CONTROL, "no-cache")
+                    .header(USER_AGENT, ST_USER_AGENT)
+                    .header(CONNECTION, "close")
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                tracker.connection_opened();
+                let mut total_transferred = 0;
+                while SystemTime::now().duration_since(start_time)? < length
+                    && !cancellation_token.is_cancelled()
+                {
+                    match response.chunk().await? {
+                        Some(chunk) => {
+                            total_transferred += chunk.len();
+                            tracker.add(chunk.len());
+                        }
+                        None => break,
+                    }
+                }
+                tracker.connection_closed();
+
+                Ok::<_, SpeedTestError>(total_transferred)
+            }
+        })
+        .buffer_unordered(config.threads.download.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let total_transferred: usize = total_transferred_per_request.iter().sum();
+
+    let end_time = SystemTime::now();
+    let duration = end_time.duration_since(start_time)?;
+
+    let mut measurement = SpeedMeasurement {
+        size: total_transferred,
+        duration,
+        requests: vec![],
+        sample_interval: speedtest::SAMPLE_INTERVAL,
+        samples: tracker.samples(duration),
+        warm_up: config.length.warm_up,
+        statistics: None,
+    };
+    measurement.statistics = Statistics::from_values(&measurement.interval_bps());
+
+    speedtest::adjust_upload_threads(config, &measurement);
+
+    Ok(measurement)
+}
+
+/// Size of the chunks an upload body is streamed in.
+const UPLOAD_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Upload body streamed in chunks, counting the bytes handed to the connection. The
+/// request is cut off once the upload test length is reached or the test is cancelled.
+fn upload_body<F>(
+    size: usize,
+    sent: Arc<AtomicUsize>,
+    tracker: Arc<TransferTracker<F>>,
+    start_time: SystemTime,
+    length: Duration,
+    cancellation_token: CancellationToken,
+) -> Body
+where
+    F: Fn(&SpeedTestProgress) + Send + Sync + 'static,
+{
+    let mut content = speedtest::upload_content(size);
+    let chunks = std::iter::from_fn(move || {
+        let chunk: Vec<u8> = content.by_ref().take(UPLOAD_CHUNK_SIZE).copied().collect();
+        (!chunk.is_empty()).then_some(chunk)
+    });
+    Body::wrap_stream(stream::iter(chunks).map(move |chunk| {
+        let elapsed = SystemTime::now()
+            .duration_since(start_time)
+            .map_err(io::Error::other)?;
+        if elapsed >= length {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "upload test length reached",
+            ));
+        }
+        if cancellation_token.is_cancelled() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "upload test cancelled",
+            ));
+        }
+        sent.fetch_add(chunk.len(), Ordering::Relaxed);
+        tracker.add(chunk.len());
+        Ok(chunk)
+    }))
+}
+
+/// Runs the upload test. When cancelled, the measurement covers what was
+/// uploaded up to that point.
+pub async fn test_upload_with_progress_and_config<F>(
+    server: &SpeedTestServer,
+    progress_callback: F,
+    config: &SpeedTestConfig,
+    cancellation_token: &CancellationToken,
+) -> Result<SpeedMeasurement, SpeedTestError>
+where
+    F: Fn(&SpeedTestProgress) + Send + Sync + 'static,
+{
+    info!("Testing Upload speed");
+
+    let sizes = speedtest::upload_sizes(config);
+
+    let best_url = Url::parse(&server.url)?;
+
+    let request_count = config.upload_max;
+
+    let client = Client::new();
+
+    // Start Timer
+    let start_time = SystemTime::now();
+    let tracker = Arc::new(TransferTracker::new(progress_callback));
+
+    info!("Upload Threads: {}", config.threads.upload);
+    let request_measurements = stream::iter(sizes.into_iter().take(request_count))
+        .map(|size| {
+            let client = &client;
+            let tracker = &tracker;
+            let best_url = best_url.clone();
+            async move {
+                let elapsed = SystemTime::now().duration_since(start_time)?;
+                let Some(remaining) = config
+                    .length
+                    .upload
+                    .checked_sub(elapsed)
+                    .filter(|remaining| !remaining.is_zero() && !cancellation_token.is_cancelled())
+                else {
+                    return Ok(None);
+                };
+
+                let request_start_time = SystemTime::now();
+                let sent = Arc::new(AtomicUsize::new(0));
+                let body = upload_body(
+                    size,
+                    sent.clone(),
+                    tracker.clone(),
+                    start_time,
+                    config.length.upload,
+                    cancellation_token.clone(),
+                );
+                info!("Requesting {best_url}");
+                tracker.connection_opened();
+                // The timeout also cuts off a request whose body is stuck on a full
+                // connection, where the body is not asked for more.
+                let response = client
+                    .post(best_url)
+                    .header(USER_AGENT, ST_USER_AGENT)
+                    .header(CONNECTION, "close")
+                    .header(CONTENT_LENGTH, size)
+                    .timeout(remaining)
+                    .body(body)
+                    .send()
+                    .await
+                    .and_then(Response::error_for_status);
+                tracker.connection_closed();
+                let error = match response {
+                    Ok(_) => None,
+                    // Requests cut off by the end of the test still sent what they sent.
+                    Err(err)
+                        if !err.is_status()
+                            && (SystemTime::now().duration_since(start_time)?
+                                >= config.length.upload
+                                || cancellation_token.is_cancelled()) =>
+                    {
+                        None
+                    }
+                    Err(err) => {
+                        info!("Upload request failed: {err:?}");
+                        Some(err.to_string())
+                    }
+                };
+
+                Ok::<_, SpeedTestError>(Some(SpeedTestRequestMeasurement {
+                    size,
+                    sent: sent.load(Ordering::Relaxed),
+                    duration: SystemTime::now().duration_since(request_start_time)?,
+                    error,
+                }))
+            }
+        })
+        .buffer_unordered(config.threads.upload.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let requests: Vec<_> = request_measurements.into_iter().flatten().collect();
+    speedtest::check_upload_requests(&requests)?;
+
+    let total_transferred: usize = requests
+        .iter()
+        .filter(|r| r.error.is_none())
+        .map(|r| r.sent)
+        .sum();
+
+    let end_time = SystemTime::now();
+    let duration = end_time.duration_since(start_time)?;
+
+    let mut measurement = SpeedMeasurement {
+        size: total_transferred,
+        duration,
+        requests,
+        sample_interval: speedtest::SAMPLE_INTERVAL,
+        samples: tracker.samples(duration),
+        warm_up: config.length.warm_up,
+        statistics: None,
+    };
+    measurement.statistics = Statistics::from_values(&measurement.interval_bps());
+
+    Ok(measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sample_server, small_transfer_config};
+
+    #[tokio::test]
+    async fn test_get_configuration() {
+        let mut server = mockito::Server::new_async().await;
+
+        let _m = server
+            .mock("GET", "/speedtest-config.php")
+            .with_status(200)
+            .with_body_from_file("tests/config/config.php.xml")
+            .create_async()
+            .await;
+
+        let endpoints = SpeedTestEndpoints::with_base_url(&server.url());
+        let config = get_configuration(&endpoints, None).await.unwrap();
+        assert_eq!("Cox Communications", config.client.isp);
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let mut server = mockito::Server::new_async().await;
+
+        let _m = server
+            .mock(
+                "GET",
+                mockito::Matcher::Regex("random350x350.jpg".to_owned()),
+            )
+            .with_status(200)
+            .with_body(vec![0u8; 4096])
+            .expect(2)
+            .create_async()
+            .await;
+
+        let mut config = small_transfer_config();
+        config.counts.download = 2;
+        config.threads.download = 2;
+        let measurement = test_download_with_progress_and_config(
+            &sample_server(&server.url()),
+            |_| {},
+            &mut config,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(2 * 4096, measurement.size);
+    }
+
+    #[tokio::test]
+    async fn test_download_honours_download_length() {
+        let mut server = mockito::Server::new_async().await;
+
+        // Trickle the body out for far longer than the download length.
+        let m = server
+            .mock(
+                "GET",
+                mockito::Matcher::Regex("random350x350.jpg".to_owned()),
+            )
+            .with_status(200)
+            .with_chunked_body(|w| {
+                for _ in 0..100 {
+                    w.write_all(&[0u8; 1024])?;
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Ok(())
+            })
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut config = small_transfer_config();
+        config.counts.download = 4;
+        config.length.set_download(Duration::from_millis(500));
+        let measurement = test_download_with_progress_and_config(
+            &sample_server(&server.url()),
+            |_| {},
+            &mut config,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(measurement.duration < Duration::from_secs(2));
+        assert!(measurement.size < 100 * 1024);
+        m.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let mut server = mockito::Server::new_async().await;
+
+        let _m = server
+            .mock("POST", "/speedtest/upload.php")
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let mut config = small_transfer_config();
+        config.counts.upload = 2;
+        config.threads.upload = 2;
+        config.upload_max = 2;
+        let measurement = test_upload_with_progress_and_config(
+            &sample_server(&server.url()),
+            |_| {},
+            &config,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(2 * 32768, measurement.size);
+    }
+
+    #[tokio::test]
+    async fn test_upload_cuts_off_requests_at_upload_length() {
+        // Accept the upload but never read it, so that the request stalls once the
+        // connection's buffers are full.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let _connection = listener.accept();
+            std::thread::sleep(Duration::from_secs(5));
+        });
+
+        let mut config = small_transfer_config();
+        config.sizes.upload = vec![64 * 1024 * 1024];
+        config.counts.upload = 1;
+        config.upload_max = 1;
+        config.length.set_upload(Duration::from_millis(500));
+        let measurement = test_upload_with_progress_and_config(
+            &sample_server(&url),
+            |_| {},
+            &config,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(measurement.duration < Duration::from_secs(2));
+        assert_eq!(1, measurement.requests.len());
+        let request = &measurement.requests[0];
+        assert!(request.error.is_none());
+        assert!(request.sent > 0);
+        assert!(request.sent < request.size);
+        assert_eq!(request.sent, measurement.size);
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::SpeedTestError;
+
+/// How long cached responses are used before they are downloaded again, unless
+/// configured otherwise. The configuration includes the client's location, so this is
+/// kept short for clients that move between networks.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A response stored in the cache.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpeedTestCacheEntry {
+    pub url: String,
+    pub timestamp: DateTime<Utc>,
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+/// On-disk cache of the raw configuration and server list responses, one file per URL.
+#[derive(Clone, Debug)]
+pub struct SpeedTestCache {
+    dir: PathBuf,
+    ttl: Duration,
+    refresh: bool,
+}
+
+impl SpeedTestCache {
+    pub fn new(dir: impl Into<PathBuf>) -> SpeedTestCache {
+        SpeedTestCache {
+            dir: dir.into(),
+            ttl: DEFAULT_CACHE_TTL,
+            refresh: false,
+        }
+    }
+
+    /// `speedtest-rs` in the user's cache directory, e.g. `$XDG_CACHE_HOME/speedtest-rs`.
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("speedtest-rs"))
+    }
+
+    /// Age after which an entry is stale. Defaults to [`DEFAULT_CACHE_TTL`].
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Whether to ignore the stored entries, while still storing new ones.
+    pub fn refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The fresh entry for `url`, if any.
+    pub fn get(&self, url: &str) -> Option<SpeedTestCacheEntry> {
+        if self.refresh {
+            return None;
+        }
+        let entry: SpeedTestCacheEntry =
+            serde_json::from_slice(&fs::read(self.entry_path(url)).ok()?).ok()?;
+        let age = (Utc::now() - entry.timestamp).to_std().unwrap_or_default();
+        (entry.url == url && age < self.ttl).then_some(entry)
+    }
+
+    pub fn put(
+        &self,
+        url: &str,
+        content_type: Option<&str>,
+        body: &str,
+    ) -> Result<(), SpeedTestError> {
+        let entry = SpeedTestCacheEntry {
+            url: url.to_owned(),
+            timestamp: Utc::now(),
+            content_type: content_type.map(str::to_owned),
+            body: body.to_owned(),
+        };
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.entry_path(url), serde_json::to_vec(&entry)?)?;
+        Ok(())
+    }
+
+    fn entry_path(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{:x}.json", md5::compute(url)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cache(name: &str) -> SpeedTestCache {
+        let dir =
+            std::env::temp_dir().join(format!("speedtest-rs-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        SpeedTestCache::new(dir)
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let cache = temp_cache("put");
+        let url = "http://www.speedtest.net/speedtest-servers.php";
+        assert!(cache.get(url).is_none());
+
+        cache.put(url, Some("text/xml"), "<settings/>").unwrap();
+        let entry = cache.get(url).unwrap();
+        assert_eq!("<settings/>", entry.body);
+        assert_eq!(Some("text/xml"), entry.content_type.as_deref());
+        assert!(cache
+            .get("http://c.speedtest.net/speedtest-servers.php")
+            .is_none());
+
+        assert!(cache.clone().refresh(true).get(url).is_none());
+        assert!(cache.clone().ttl(Duration::ZERO).get(url).is_none());
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
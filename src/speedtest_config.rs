@@ -1,43 +1,98 @@
 #![allow(dead_code)]
 
 use crate::{distance::EarthLocation, error::SpeedTestError};
+use serde::{Deserialize, Serialize};
 use std::{net::Ipv4Addr, time::Duration};
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SpeedTestClientConfig {
-    pub ip: Ipv4Addr,
+    /// Public address of the client, `None` if unknown, e.g. when the configuration
+    /// was not downloaded
+    pub ip: Option<Ipv4Addr>,
     pub isp: String,
 }
 
-impl Default for SpeedTestClientConfig {
-    fn default() -> Self {
-        SpeedTestClientConfig {
-            ip: Ipv4Addr::new(127, 0, 0, 1),
-            isp: String::default(),
-        }
-    }
-}
-
-#[derive(Default)]
+#[derive(Debug)]
 pub struct SpeedTestSizeConfig {
     pub upload: Vec<usize>,
     pub download: Vec<usize>,
 }
 
-#[derive(Default)]
+impl SpeedTestSizeConfig {
+    /// Sizes for an upload `ratio` from the configuration, between 1 and 7. Higher
+    /// ratios leave out the smaller upload sizes.
+    pub fn with_upload_ratio(ratio: usize) -> Option<SpeedTestSizeConfig> {
+        let up_sizes = [32768usize, 65536, 131072, 262144, 524288, 1048576, 7340032];
+        Some(SpeedTestSizeConfig {
+            upload: up_sizes
+                .get(ratio.checked_sub(1)?..)
+                .filter(|sizes| !sizes.is_empty())?
+                .to_vec(),
+            download: vec![350usize, 500, 750, 1000, 1500, 2000, 2500, 3000, 3500, 4000],
+        })
+    }
+}
+
+impl Default for SpeedTestSizeConfig {
+    fn default() -> Self {
+        SpeedTestSizeConfig::with_upload_ratio(DEFAULT_UPLOAD_RATIO)
+            .expect("the default upload ratio is valid")
+    }
+}
+
+#[derive(Debug)]
 pub struct SpeedTestCountsConfig {
     pub upload: usize,
     pub download: usize,
 }
 
-#[derive(Default)]
+impl Default for SpeedTestCountsConfig {
+    fn default() -> Self {
+        SpeedTestCountsConfig {
+            upload: upload_count(DEFAULT_UPLOAD_MAX, &SpeedTestSizeConfig::default()),
+            download: 4,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct SpeedTestThreadsConfig {
     pub upload: usize,
     pub download: usize,
 }
 
+impl Default for SpeedTestThreadsConfig {
+    fn default() -> Self {
+        SpeedTestThreadsConfig {
+            upload: 2,
+            download: 8,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct SpeedTestLengthConfig {
     pub upload: Duration,
     pub download: Duration,
+    /// Initial part of each test left out of the steady-state throughput
+    pub warm_up: Duration,
+}
+
+impl SpeedTestLengthConfig {
+    /// Override how long the download test runs for.
+    pub fn set_download(&mut self, length: Duration) {
+        self.download = length;
+    }
+
+    /// Override how long the upload test runs for.
+    pub fn set_upload(&mut self, length: Duration) {
+        self.upload = length;
+    }
+
+    /// Set how much of the start of each test to discard as warm-up.
+    pub fn set_warm_up(&mut self, warm_up: Duration) {
+        self.warm_up = warm_up;
+    }
 }
 
 impl Default for SpeedTestLengthConfig {
@@ -45,11 +100,24 @@ impl Default for SpeedTestLengthConfig {
         SpeedTestLengthConfig {
             upload: Duration::from_secs(10),
             download: Duration::from_secs(10),
+            warm_up: Duration::ZERO,
         }
     }
 }
 
-#[derive(Default)]
+/// Upload ratio of a typical speedtest.net configuration.
+const DEFAULT_UPLOAD_RATIO: usize = 5;
+
+/// Maximum number of upload requests of a typical speedtest.net configuration.
+const DEFAULT_UPLOAD_MAX: usize = 50;
+
+fn upload_count(upload_max: usize, sizes: &SpeedTestSizeConfig) -> usize {
+    (upload_max as f32 / sizes.upload.len() as f32).ceil() as usize
+}
+
+/// The default matches a typical speedtest.net configuration with an unknown client,
+/// for use where the configuration cannot be downloaded.
+#[derive(Debug)]
 pub struct SpeedTestConfig {
     pub client: SpeedTestClientConfig,
     pub ignore_servers: Vec<u32>,
@@ -61,6 +129,21 @@ pub struct SpeedTestConfig {
     pub location: EarthLocation,
 }
 
+impl Default for SpeedTestConfig {
+    fn default() -> Self {
+        SpeedTestConfig {
+            client: SpeedTestClientConfig::default(),
+            ignore_servers: vec![],
+            sizes: SpeedTestSizeConfig::default(),
+            counts: SpeedTestCountsConfig::default(),
+            threads: SpeedTestThreadsConfig::default(),
+            length: SpeedTestLengthConfig::default(),
+            upload_max: DEFAULT_UPLOAD_MAX,
+            location: EarthLocation::default(),
+        }
+    }
+}
+
 impl SpeedTestConfig {
     pub fn parse(config_xml: &str) -> Result<SpeedTestConfig, SpeedTestError> {
         let document = roxmltree::Document::parse(config_xml)?;
@@ -100,19 +183,10 @@ impl SpeedTestConfig {
             .ok_or(SpeedTestError::ConfigParseError)?
             .parse::<usize>()?;
 
-        let up_sizes = [32768usize, 65536, 131072, 262144, 524288, 1048576, 7340032];
-
-        let sizes = SpeedTestSizeConfig {
-            upload: up_sizes
-                .get(ratio - 1..)
-                .ok_or(SpeedTestError::ConfigParseError)?
-                .to_vec(),
-            download: vec![350usize, 500, 750, 1000, 1500, 2000, 2500, 3000, 3500, 4000],
-        };
-
-        let size_count = sizes.upload.len();
+        let sizes = SpeedTestSizeConfig::with_upload_ratio(ratio)
+            .ok_or(SpeedTestError::ConfigParseError)?;
 
-        let upload_count = (upload_max as f32 / size_count as f32).ceil() as usize;
+        let upload_count = upload_count(upload_max, &sizes);
 
         let counts = SpeedTestCountsConfig {
             upload: upload_count,
@@ -145,13 +219,16 @@ impl SpeedTestConfig {
                 .ok_or(SpeedTestError::ConfigParseError)?
                 .parse::<u64>()
                 .map(Duration::from_secs)?,
+            warm_up: Duration::ZERO,
         };
 
         let client = SpeedTestClientConfig {
-            ip: client_node
-                .attribute("ip")
-                .ok_or(SpeedTestError::ConfigParseError)?
-                .parse()?,
+            ip: Some(
+                client_node
+                    .attribute("ip")
+                    .ok_or(SpeedTestError::ConfigParseError)?
+                    .parse()?,
+            ),
             isp: client_node
                 .attribute("isp")
                 .ok_or(SpeedTestError::ConfigParseError)?
@@ -188,7 +265,7 @@ mod tests {
     fn test_parse_config_xml() {
         let config =
             SpeedTestConfig::parse(include_str!("../tests/config/config.php.xml")).unwrap();
-        assert_eq!("174.79.12.26", config.client.ip.to_string());
+        assert_eq!(Some(Ipv4Addr::new(174, 79, 12, 26)), config.client.ip);
         assert_eq!(
             EarthLocation {
                 latitude: 32.9954,
@@ -199,6 +276,25 @@ mod tests {
         assert_eq!("Cox Communications", config.client.isp);
     }
 
+    #[test]
+    fn test_default_matches_typical_config() {
+        let parsed =
+            SpeedTestConfig::parse(include_str!("../tests/config/config.php.xml")).unwrap();
+        let default = SpeedTestConfig::default();
+        assert_eq!(parsed.sizes.upload, default.sizes.upload);
+        assert_eq!(parsed.sizes.download, default.sizes.download);
+        assert_eq!(parsed.counts.upload, default.counts.upload);
+        assert_eq!(parsed.counts.download, default.counts.download);
+        assert_eq!(parsed.threads.upload, default.threads.upload);
+        assert_eq!(parsed.threads.download, default.threads.download);
+        assert_eq!(parsed.length.download, default.length.download);
+        assert_eq!(parsed.upload_max, default.upload_max);
+        assert_eq!(None, default.client.ip);
+
+        assert!(SpeedTestSizeConfig::with_upload_ratio(0).is_none());
+        assert!(SpeedTestSizeConfig::with_upload_ratio(8).is_none());
+    }
+
     #[test]
     fn test_parse_config_xml_83() {
         let config =
@@ -22,6 +22,16 @@ pub struct SpeedTestCsvResult<'a> {
     pub share: &'a str,
     #[serde(rename = "IP Address")]
     pub ip_address: &'a str,
+    #[serde(rename = "Ping Min")]
+    pub ping_min: &'a str,
+    #[serde(rename = "Ping Max")]
+    pub ping_max: &'a str,
+    #[serde(rename = "Ping Median")]
+    pub ping_median: &'a str,
+    #[serde(rename = "Jitter")]
+    pub jitter: &'a str,
+    #[serde(rename = "Packet Loss")]
+    pub packet_loss: &'a str,
 }
 
 impl<'a> SpeedTestCsvResult<'a> {
@@ -30,7 +40,8 @@ impl<'a> SpeedTestCsvResult<'a> {
         // Blocked on:
         // * https://github.com/BurntSushi/rust-csv/issues/161 being implemented or solved
         // * https://github.com/BurntSushi/rust-csv/pull/193/files, like in this?
-        "Server ID,Sponsor,Server Name,Timestamp,Distance,Ping,Download,Upload,Share,IP Address"
+        "Server ID,Sponsor,Server Name,Timestamp,Distance,Ping,Download,Upload,Share,IP Address,\
+Ping Min,Ping Max,Ping Median,Jitter,Packet Loss"
             .to_string()
     }
 }
@@ -42,7 +53,7 @@ mod tests {
 
     #[test]
     fn test_header_serialize() -> Result<(), Box<dyn Error>> {
-        let original = "Server ID,Sponsor,Server Name,Timestamp,Distance,Ping,Download,Upload,Share,IP Address";
+        let original = "Server ID,Sponsor,Server Name,Timestamp,Distance,Ping,Download,Upload,Share,IP Address,Ping Min,Ping Max,Ping Median,Jitter,Packet Loss";
 
         let results = SpeedTestCsvResult::default();
 
@@ -0,0 +1,299 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::speedtest::{
+    SpeedMeasurement, SpeedTestLatencyTestResult, SpeedTestResult, SpeedTestServer,
+    SpeedTestServerAttempt, SAMPLE_INTERVAL,
+};
+use crate::speedtest_loaded_latency::BufferbloatGrade;
+
+/// Result document in the shape of `speedtest-cli --json`.
+/// Speeds are in bit/s and the ping in milliseconds.
+#[derive(Debug, Serialize)]
+pub struct SpeedTestJsonResult<'a> {
+    pub download: f64,
+    pub upload: f64,
+    /// Download speed after the warm-up, only present when a warm-up was configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_steady_state: Option<f64>,
+    /// Upload speed after the warm-up, only present when a warm-up was configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_steady_state: Option<f64>,
+    pub ping: f64,
+    pub server: SpeedTestJsonServer<'a>,
+    pub timestamp: String,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub share: Option<&'a str>,
+    pub client: SpeedTestJsonClient<'a>,
+    pub latency: SpeedTestJsonLatency,
+    /// Only present when loaded latency was measured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loaded_latency: Option<SpeedTestJsonLoadedLatency>,
+    /// Servers that failed during a phase
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub failed_attempts: &'a [SpeedTestServerAttempt],
+    /// ID of the server the download was measured on, only present when it isn't `server`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_server: Option<String>,
+    /// ID of the server the upload was measured on, only present when it isn't `server`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_server: Option<String>,
+    pub samples: SpeedTestJsonSamples<'a>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpeedTestJsonServer<'a> {
+    pub url: &'a str,
+    pub lat: f32,
+    pub lon: f32,
+    pub name: &'a str,
+    pub country: &'a str,
+    pub cc: &'a str,
+    pub sponsor: &'a str,
+    pub id: String,
+    pub host: &'a str,
+    /// Distance in km
+    pub d: Option<f32>,
+    pub latency: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpeedTestJsonClient<'a> {
+    pub ip: String,
+    pub isp: &'a str,
+}
+
+/// Latency statistics of the selected server, in milliseconds.
+#[derive(Debug, Serialize)]
+pub struct SpeedTestJsonLatency {
+    pub samples: Vec<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub median: Option<f64>,
+    pub jitter: f64,
+    /// Percentage of failed samples
+    pub packet_loss: f64,
+}
+
+impl SpeedTestJsonLatency {
+    fn from_latency(latency: &SpeedTestLatencyTestResult) -> SpeedTestJsonLatency {
+        SpeedTestJsonLatency {
+            samples: latency.samples.iter().copied().map(millis).collect(),
+            min: latency.min().map(millis),
+            max: latency.max().map(millis),
+            median: latency.median().map(millis),
+            jitter: millis(latency.jitter()),
+            packet_loss: latency.loss_percent(),
+        }
+    }
+}
+
+/// Latency while the download and upload tests ran.
+#[derive(Debug, Serialize)]
+pub struct SpeedTestJsonLoadedLatency {
+    pub download: Option<SpeedTestJsonLatency>,
+    pub upload: Option<SpeedTestJsonLatency>,
+    pub bufferbloat_grade: Option<BufferbloatGrade>,
+}
+
+/// Bytes transferred per sample interval, see [`SpeedMeasurement::samples`].
+#[derive(Debug, Serialize)]
+pub struct SpeedTestJsonSamples<'a> {
+    /// Interval length in milliseconds
+    pub interval: f64,
+    pub download: &'a [usize],
+    pub upload: &'a [usize],
+}
+
+impl<'a> SpeedTestJsonResult<'a> {
+    pub fn from_result(result: &'a SpeedTestResult) -> SpeedTestJsonResult<'a> {
+        let server = &result.server;
+        let latency = &result.latency_measurement;
+        let ping = millis(latency.latency);
+        SpeedTestJsonResult {
+            download: result
+                .download_measurement
+                .as_ref()
+                .map_or(0.0, |x| x.bps_f64()),
+            upload: result
+                .upload_measurement
+                .as_ref()
+                .map_or(0.0, |x| x.bps_f64()),
+            download_steady_state: result
+                .download_measurement
+                .as_ref()
+                .and_then(|x| x.steady_state_bps()),
+            upload_steady_state: result
+                .upload_measurement
+                .as_ref()
+                .and_then(|x| x.steady_state_bps()),
+            ping,
+            server: SpeedTestJsonServer {
+                url: &server.url,
+                lat: server.location.latitude,
+                lon: server.location.longitude,
+                name: &server.name,
+                country: &server.country,
+                cc: &server.country_code,
+                sponsor: &server.sponsor,
+                id: server.id.to_string(),
+                host: &server.host,
+                d: server.distance,
+                latency: ping,
+            },
+            timestamp: result
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            bytes_sent: result.upload_measurement.as_ref().map_or(0, |x| x.size),
+            bytes_received: result.download_measurement.as_ref().map_or(0, |x| x.size),
+            share: result.share_url.as_deref(),
+            client: SpeedTestJsonClient {
+                ip: result
+                    .client
+                    .ip
+                    .map_or_else(String::new, |ip| ip.to_string()),
+                isp: &result.client.isp,
+            },
+            latency: SpeedTestJsonLatency::from_latency(latency),
+            loaded_latency: (result.download_loaded_latency.is_some()
+                || result.upload_loaded_latency.is_some())
+            .then(|| SpeedTestJsonLoadedLatency {
+                download: result
+                    .download_loaded_latency
+                    .as_ref()
+                    .map(SpeedTestJsonLatency::from_latency),
+                upload: result
+                    .upload_loaded_latency
+                    .as_ref()
+                    .map(SpeedTestJsonLatency::from_latency),
+                bufferbloat_grade: result.bufferbloat_grade(),
+            }),
+            failed_attempts: &result.failed_attempts,
+            download_server: other_server_id(server, &result.download_server),
+            upload_server: other_server_id(server, &result.upload_server),
+            samples: SpeedTestJsonSamples {
+                interval: millis(
+                    result
+                        .download_measurement
+                        .as_ref()
+                        .or(result.upload_measurement.as_ref())
+                        .map_or(SAMPLE_INTERVAL, |x| x.sample_interval),
+                ),
+                download: samples(&result.download_measurement),
+                upload: samples(&result.upload_measurement),
+            },
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn other_server_id(
+    server: &SpeedTestServer,
+    phase_server: &Option<SpeedTestServer>,
+) -> Option<String> {
+    phase_server
+        .as_ref()
+        .filter(|phase_server| phase_server.url != server.url)
+        .map(|phase_server| phase_server.id.to_string())
+}
+
+fn samples(measurement: &Option<SpeedMeasurement>) -> &[usize] {
+    measurement.as_ref().map_or(&[], |x| &x.samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::EarthLocation;
+    use crate::speedtest::SpeedTestServer;
+    use crate::speedtest_config::SpeedTestClientConfig;
+    use chrono::{TimeZone, Utc};
+    use std::error::Error;
+
+    #[test]
+    fn test_from_result() -> Result<(), Box<dyn Error>> {
+        let server = SpeedTestServer {
+            country: "United States".to_owned(),
+            country_code: "US".to_owned(),
+            host: "speedtest.example.com:8080".to_owned(),
+            id: 5116,
+            location: EarthLocation {
+                latitude: 32.9954,
+                longitude: -117.0753,
+            },
+            distance: Some(12.5),
+            name: "Los Angeles, CA".to_owned(),
+            sponsor: "Example".to_owned(),
+            url: "http://speedtest.example.com/speedtest/upload.php".to_owned(),
+        };
+        let latency_measurement = SpeedTestLatencyTestResult {
+            server: server.clone(),
+            latency: Duration::from_micros(26_500),
+            samples: vec![
+                Duration::from_millis(25),
+                Duration::from_millis(28),
+                Duration::from_micros(26_500),
+            ],
+            failed_samples: 1,
+            error: None,
+        };
+        let result = SpeedTestResult {
+            download_measurement: Some(SpeedMeasurement {
+                size: 1_000_000,
+                duration: Duration::from_secs(1),
+                sample_interval: Duration::from_millis(250),
+                samples: vec![250_000; 4],
+                warm_up: Duration::from_millis(500),
+                ..SpeedMeasurement::default()
+            }),
+            upload_measurement: None,
+            server: server.clone(),
+            latency_measurement,
+            client: SpeedTestClientConfig::default(),
+            timestamp: Utc.with_ymd_and_hms(2024, 7, 27, 0, 0, 0).unwrap(),
+            share_url: None,
+            download_loaded_latency: Some(SpeedTestLatencyTestResult::from_round_trips(
+                server.clone(),
+                &[Duration::from_millis(130)],
+                0,
+            )),
+            upload_loaded_latency: None,
+            failed_attempts: vec![],
+            download_server: None,
+            upload_server: None,
+        };
+
+        let json = serde_json::to_value(SpeedTestJsonResult::from_result(&result))?;
+        assert_eq!(8_000_000.0, json["download"]);
+        assert_eq!(0.0, json["upload"]);
+        assert_eq!(8_000_000.0, json["download_steady_state"]);
+        assert!(json.get("upload_steady_state").is_none());
+        assert_eq!(26.5, json["ping"]);
+        assert_eq!("5116", json["server"]["id"]);
+        assert_eq!("US", json["server"]["cc"]);
+        assert_eq!(1_000_000, json["bytes_received"]);
+        assert_eq!(0, json["bytes_sent"]);
+        assert_eq!("2024-07-27T00:00:00.000000Z", json["timestamp"]);
+        assert_eq!("", json["client"]["ip"]);
+        assert!(json["share"].is_null());
+        assert_eq!(3, json["latency"]["samples"].as_array().unwrap().len());
+        assert_eq!(25.0, json["latency"]["min"]);
+        assert_eq!(28.0, json["latency"]["max"]);
+        assert_eq!(26.5, json["latency"]["median"]);
+        assert_eq!(2.25, json["latency"]["jitter"]);
+        assert_eq!(25.0, json["latency"]["packet_loss"]);
+        assert_eq!(65.0, json["loaded_latency"]["download"]["median"]);
+        assert!(json["loaded_latency"]["upload"].is_null());
+        // 65 ms loaded against 26.5 ms idle is 77 ms more per round trip.
+        assert_eq!("C", json["loaded_latency"]["bufferbloat_grade"]);
+        assert_eq!(250.0, json["samples"]["interval"]);
+        assert_eq!(4, json["samples"]["download"].as_array().unwrap().len());
+        assert!(json["samples"]["upload"].as_array().unwrap().is_empty());
+        Ok(())
+    }
+}
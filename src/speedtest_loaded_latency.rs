@@ -0,0 +1,187 @@
+use std::fmt;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "log")]
+use log::info;
+
+#[cfg(not(feature = "log"))]
+use super::log::info;
+
+use reqwest::blocking::Client;
+use reqwest::header::{CONNECTION, USER_AGENT};
+use serde::{Deserialize, Serialize};
+
+use crate::error::SpeedTestError;
+use crate::speedtest::{
+    latency_url, CancellationToken, SpeedTestLatencyTestResult, SpeedTestServer, ST_USER_AGENT,
+};
+
+/// Time between the start of consecutive loaded latency samples.
+const PROBE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Samples taking longer than this count as lost.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Repeatedly samples the latency of a server in the background, e.g. while a
+/// download or upload test saturates the link.
+pub struct LatencyProbe {
+    server: SpeedTestServer,
+    stop: CancellationToken,
+    handle: Option<JoinHandle<(Vec<Duration>, usize)>>,
+}
+
+impl LatencyProbe {
+    pub fn start(server: &SpeedTestServer) -> Result<LatencyProbe, SpeedTestError> {
+        let latency_path = latency_url(server)?;
+        let client = Client::builder().timeout(PROBE_TIMEOUT).build()?;
+        let stop = CancellationToken::new();
+        let thread_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut round_trips = vec![];
+            let mut failed_samples = 0;
+            while !thread_stop.is_cancelled() {
+                let start_time = Instant::now();
+                let res = client
+                    .get(&latency_path)
+                    .header(CONNECTION, "close")
+                    .header(USER_AGENT, ST_USER_AGENT)
+                    .send()
+                    .and_then(|res| res.bytes());
+                match res {
+                    Ok(_) => round_trips.push(start_time.elapsed()),
+                    Err(_err) => {
+                        info!("Loaded latency sample failed: {_err:?}");
+                        failed_samples += 1;
+                    }
+                }
+                thread::sleep(PROBE_INTERVAL.saturating_sub(start_time.elapsed()));
+            }
+            (round_trips, failed_samples)
+        });
+        Ok(LatencyProbe {
+            server: server.clone(),
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Stop sampling and summarize the samples taken so far.
+    pub fn stop(mut self) -> SpeedTestLatencyTestResult {
+        self.stop.cancel();
+        let (round_trips, failed_samples) = self
+            .handle
+            .take()
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default();
+        SpeedTestLatencyTestResult::from_round_trips(
+            self.server.clone(),
+            &round_trips,
+            failed_samples,
+        )
+    }
+}
+
+impl Drop for LatencyProbe {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// Grade for how much latency rises under load, from `A+` (barely) to `F`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum BufferbloatGrade {
+    #[serde(rename = "A+")]
+    APlus,
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+impl BufferbloatGrade {
+    /// Grade the increase of the median round trip time from `idle` to `loaded`.
+    /// `None` if either has no successful samples.
+    pub fn grade(
+        idle: &SpeedTestLatencyTestResult,
+        loaded: &SpeedTestLatencyTestResult,
+    ) -> Option<BufferbloatGrade> {
+        // Samples are trip times, half of the round trip.
+        let increase = loaded.median()?.saturating_sub(idle.median()?) * 2;
+        Some(match increase.as_millis() {
+            0..5 => BufferbloatGrade::APlus,
+            5..30 => BufferbloatGrade::A,
+            30..60 => BufferbloatGrade::B,
+            60..200 => BufferbloatGrade::C,
+            200..400 => BufferbloatGrade::D,
+            _ => BufferbloatGrade::F,
+        })
+    }
+}
+
+impl fmt::Display for BufferbloatGrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BufferbloatGrade::APlus => "A+",
+            BufferbloatGrade::A => "A",
+            BufferbloatGrade::B => "B",
+            BufferbloatGrade::C => "C",
+            BufferbloatGrade::D => "D",
+            BufferbloatGrade::F => "F",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sample_server;
+
+    fn latency_result(round_trip_ms: u64) -> SpeedTestLatencyTestResult {
+        SpeedTestLatencyTestResult::from_round_trips(
+            sample_server("http://localhost"),
+            &[Duration::from_millis(round_trip_ms)],
+            0,
+        )
+    }
+
+    #[test]
+    fn test_grade() {
+        let idle = latency_result(20);
+        assert_eq!(
+            Some(BufferbloatGrade::APlus),
+            BufferbloatGrade::grade(&idle, &latency_result(22))
+        );
+        assert_eq!(
+            Some(BufferbloatGrade::B),
+            BufferbloatGrade::grade(&idle, &latency_result(70))
+        );
+        assert_eq!(
+            Some(BufferbloatGrade::F),
+            BufferbloatGrade::grade(&idle, &latency_result(500))
+        );
+        assert_eq!("A+", BufferbloatGrade::APlus.to_string());
+
+        let no_samples =
+            SpeedTestLatencyTestResult::from_round_trips(sample_server("http://localhost"), &[], 3);
+        assert_eq!(None, BufferbloatGrade::grade(&idle, &no_samples));
+    }
+
+    #[test]
+    fn test_probe_samples_until_stopped() {
+        let mut server = mockito::Server::new();
+
+        let _m = server
+            .mock("GET", "/speedtest/latency.txt")
+            .with_status(200)
+            .with_body("test=test")
+            .create();
+
+        let probe = LatencyProbe::start(&sample_server(&server.url())).unwrap();
+        thread::sleep(PROBE_INTERVAL * 3);
+        let result = probe.stop();
+        assert!(!result.samples.is_empty());
+        assert_eq!(0, result.failed_samples);
+    }
+}
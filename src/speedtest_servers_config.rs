@@ -1,12 +1,106 @@
 use crate::distance::{self, EarthLocation};
 use crate::{error::SpeedTestError, speedtest::SpeedTestServer, speedtest_config::SpeedTestConfig};
+use serde_json::Value;
 use std::cmp::Ordering::Less;
+use std::collections::HashSet;
+use std::str::FromStr;
 
 pub struct SpeedTestServersConfig {
     pub servers: Vec<SpeedTestServer>,
 }
 
+/// Criteria a server has to meet, see [`SpeedTestServersConfig::filter`].
+/// Text is compared case-insensitively.
+#[derive(Clone, Debug, Default)]
+pub struct SpeedTestServerQuery {
+    /// ISO 3166 country code, e.g. `US`
+    pub country_code: Option<String>,
+    /// Part of the sponsor, usually the ISP hosting the server
+    pub sponsor: Option<String>,
+    /// Part of the name, usually the city
+    pub name: Option<String>,
+    /// Maximum distance from the client in km
+    pub max_distance: Option<f32>,
+}
+
+impl SpeedTestServerQuery {
+    pub fn matches(&self, server: &SpeedTestServer) -> bool {
+        self.country_code
+            .as_ref()
+            .is_none_or(|country_code| server.country_code.eq_ignore_ascii_case(country_code))
+            && self
+                .sponsor
+                .as_ref()
+                .is_none_or(|sponsor| contains_ignore_case(&server.sponsor, sponsor))
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| contains_ignore_case(&server.name, name))
+            && self.max_distance.is_none_or(|max_distance| {
+                server
+                    .distance
+                    .is_some_and(|distance| distance <= max_distance)
+            })
+    }
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
 impl SpeedTestServersConfig {
+    /// Parse a server list in either format, going by the `Content-Type` of the
+    /// response it came with, or by its first character if that is inconclusive.
+    pub fn parse_with_content_type(
+        server_config: &str,
+        content_type: Option<&str>,
+        config: &SpeedTestConfig,
+    ) -> Result<SpeedTestServersConfig, SpeedTestError> {
+        let is_json = match content_type {
+            Some(content_type) if content_type.contains("json") => true,
+            Some(content_type) if content_type.contains("xml") => false,
+            _ => server_config.trim_start().starts_with(['[', '{']),
+        };
+        if is_json {
+            SpeedTestServersConfig::parse_json_with_config(server_config, config)
+        } else {
+            SpeedTestServersConfig::parse_with_config(server_config, config)
+        }
+    }
+
+    /// Parse the JSON server list of `/api/js/servers`. Numbers may be given as
+    /// strings, as that API does. Distances are computed from the client location in
+    /// `config` like for the XML list.
+    pub fn parse_json_with_config(
+        server_config_json: &str,
+        config: &SpeedTestConfig,
+    ) -> Result<SpeedTestServersConfig, SpeedTestError> {
+        let entries: Vec<Value> = serde_json::from_str(server_config_json)?;
+        let servers = entries
+            .iter()
+            .map::<Result<_, SpeedTestError>, _>(|n| {
+                let location = EarthLocation {
+                    latitude: json_number(n, "lat")?,
+                    longitude: json_number(n, "lon")?,
+                };
+                Ok(SpeedTestServer {
+                    country: json_string(n, "country")?,
+                    country_code: json_string(n, "cc").unwrap_or_default(),
+                    host: json_string(n, "host")?,
+                    id: json_number(n, "id")?,
+                    location: location.clone(),
+                    distance: Some(distance::compute_distance(&config.location, &location)),
+                    name: json_string(n, "name")?,
+                    sponsor: json_string(n, "sponsor")?,
+                    url: json_string(n, "url")?,
+                })
+            })
+            .filter_map(Result::ok)
+            .filter(|server| !config.ignore_servers.contains(&server.id))
+            .collect();
+        Ok(SpeedTestServersConfig { servers })
+    }
+
     pub fn parse_with_config(
         server_config_xml: &str,
         config: &SpeedTestConfig,
@@ -31,6 +125,7 @@ impl SpeedTestServersConfig {
                         .attribute("country")
                         .ok_or(SpeedTestError::ServerParseError)?
                         .to_string(),
+                    country_code: n.attribute("cc").unwrap_or_default().to_string(),
                     host: n
                         .attribute("host")
                         .ok_or(SpeedTestError::ServerParseError)?
@@ -61,6 +156,67 @@ impl SpeedTestServersConfig {
         Ok(SpeedTestServersConfig { servers })
     }
 
+    /// Add the servers of `other` whose IDs are not in this list yet.
+    pub fn merge(&mut self, other: SpeedTestServersConfig) {
+        let mut ids: HashSet<u32> = self.servers.iter().map(|server| server.id).collect();
+        self.servers.extend(
+            other
+                .servers
+                .into_iter()
+                .filter(|server| ids.insert(server.id)),
+        );
+    }
+
+    /// Keep only the servers matching `query`.
+    pub fn filter(&mut self, query: &SpeedTestServerQuery) {
+        self.servers.retain(|server| query.matches(server));
+    }
+
+    /// Drop the servers with any of these IDs, on top of the configuration's ignored servers.
+    pub fn exclude_servers(&mut self, ids: &[u32]) {
+        self.servers.retain(|server| !ids.contains(&server.id));
+    }
+
+    /// Keep only the servers with these IDs. Fails if any of them is not in the list.
+    pub fn pin_servers(&mut self, ids: &[u32]) -> Result<(), SpeedTestError> {
+        if let Some(&missing) = ids
+            .iter()
+            .find(|&&id| !self.servers.iter().any(|server| server.id == id))
+        {
+            return Err(SpeedTestError::ServerNotFound(missing));
+        }
+        self.servers.retain(|server| ids.contains(&server.id));
+        Ok(())
+    }
+
+    /// Pick `count` servers spread out geographically from `sorted_servers`, which is
+    /// sorted by distance from the client. Starting with the closest server, each pick
+    /// is the server farthest from all servers picked so far, among the
+    /// `pool_size` closest servers.
+    pub fn diverse_servers(
+        sorted_servers: &[SpeedTestServer],
+        count: usize,
+        pool_size: usize,
+    ) -> Vec<SpeedTestServer> {
+        let pool = &sorted_servers[..pool_size.max(count).min(sorted_servers.len())];
+        let mut picked: Vec<&SpeedTestServer> = pool.iter().take(1).collect();
+        while picked.len() < count.min(pool.len()) {
+            let next = pool
+                .iter()
+                .filter(|server| !picked.iter().any(|p| p.id == server.id))
+                .max_by(|a, b| {
+                    let a_distance = distance_to_closest(a, &picked);
+                    let b_distance = distance_to_closest(b, &picked);
+                    a_distance.partial_cmp(&b_distance).unwrap_or(Less)
+                });
+            match next {
+                Some(server) => picked.push(server),
+                None => break,
+            }
+        }
+        picked.into_iter().cloned().collect()
+    }
+
     pub fn servers_sorted_by_distance(&self, config: &SpeedTestConfig) -> Vec<SpeedTestServer> {
         let location = &config.location;
         let mut sorted_servers = self.servers.clone();
@@ -73,6 +229,30 @@ impl SpeedTestServersConfig {
     }
 }
 
+fn json_string(entry: &Value, key: &str) -> Result<String, SpeedTestError> {
+    entry
+        .get(key)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or(SpeedTestError::ServerParseError)
+}
+
+fn json_number<T: FromStr>(entry: &Value, key: &str) -> Result<T, SpeedTestError> {
+    match entry.get(key) {
+        Some(Value::String(value)) => value.parse().ok(),
+        Some(Value::Number(value)) => value.to_string().parse().ok(),
+        _ => None,
+    }
+    .ok_or(SpeedTestError::ServerParseError)
+}
+
+fn distance_to_closest(server: &SpeedTestServer, others: &[&SpeedTestServer]) -> f32 {
+    others
+        .iter()
+        .map(|other| distance::compute_distance(&server.location, &other.location))
+        .fold(f32::INFINITY, f32::min)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,6 +299,150 @@ mod tests {
         assert!(!server.country.is_empty());
     }
 
+    #[test]
+    fn test_parse_speedtest_servers_json() {
+        let spt_config = SpeedTestConfig {
+            ignore_servers: vec![5256],
+            ..sample_spt_config()
+        };
+        let config_str = include_str!("../tests/config/servers.json");
+
+        let server_config =
+            SpeedTestServersConfig::parse_json_with_config(config_str, &spt_config).unwrap();
+        // 5256 is ignored and 3433 has no url.
+        let ids: Vec<u32> = server_config.servers.iter().map(|s| s.id).collect();
+        assert_eq!(vec![5827, 4961], ids);
+
+        let server = &server_config.servers[0];
+        assert_eq!("Los Angeles, CA", server.name);
+        assert_eq!("US", server.country_code);
+        assert_eq!("speedtest.socal.rr.com:8080", server.host);
+        assert_eq!(34.0522, server.location.latitude);
+        assert!(server.distance.unwrap() < 200.0);
+    }
+
+    #[test]
+    fn test_parse_with_content_type() {
+        let spt_config = sample_spt_config();
+        let json_str = include_str!("../tests/config/servers.json");
+        let xml_str = include_str!("../tests/config/stripped-servers-static.php.xml");
+
+        for content_type in [
+            Some("application/json; charset=utf-8"),
+            Some("text/plain"),
+            None,
+        ] {
+            let server_config = SpeedTestServersConfig::parse_with_content_type(
+                json_str,
+                content_type,
+                &spt_config,
+            )
+            .unwrap();
+            assert_eq!(3, server_config.servers.len());
+        }
+        for content_type in [Some("text/xml"), None] {
+            let server_config =
+                SpeedTestServersConfig::parse_with_content_type(xml_str, content_type, &spt_config)
+                    .unwrap();
+            assert!(!server_config.servers.is_empty());
+        }
+        assert!(SpeedTestServersConfig::parse_with_content_type(
+            json_str,
+            Some("text/xml"),
+            &spt_config
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_pin_and_exclude_servers() {
+        let spt_config = sample_spt_config();
+        let config_str = include_str!("../tests/config/geo-test-servers-static.php.xml");
+
+        let mut config =
+            SpeedTestServersConfig::parse_with_config(config_str, &spt_config).unwrap();
+        config.exclude_servers(&[4600]);
+        assert!(config.servers.iter().all(|s| s.id != 4600));
+
+        assert!(matches!(
+            config.pin_servers(&[4961, 4600]),
+            Err(SpeedTestError::ServerNotFound(4600))
+        ));
+        config.pin_servers(&[4961, 5256]).unwrap();
+        assert_eq!(2, config.servers.len());
+    }
+
+    #[test]
+    fn test_filter_servers() {
+        let spt_config = sample_spt_config();
+        let config_str = include_str!("../tests/config/geo-test-servers-static.php.xml");
+
+        let mut config =
+            SpeedTestServersConfig::parse_with_config(config_str, &spt_config).unwrap();
+        config.filter(&SpeedTestServerQuery {
+            country_code: Some("us".to_owned()),
+            max_distance: Some(1000.0),
+            ..SpeedTestServerQuery::default()
+        });
+        assert!(!config.servers.is_empty());
+        assert!(config
+            .servers
+            .iter()
+            .all(|s| s.country_code == "US" && s.distance.unwrap() <= 1000.0));
+
+        config.filter(&SpeedTestServerQuery {
+            sponsor: Some("time warner".to_owned()),
+            name: Some("los angeles".to_owned()),
+            ..SpeedTestServerQuery::default()
+        });
+        assert_eq!(5827, config.servers[0].id);
+        assert!(config.servers.iter().all(|s| s.name == "Los Angeles, CA"));
+    }
+
+    #[test]
+    fn test_diverse_servers() {
+        let server = |id, latitude, longitude| SpeedTestServer {
+            country: "".to_owned(),
+            country_code: "".to_owned(),
+            host: "".to_owned(),
+            id,
+            location: EarthLocation {
+                latitude,
+                longitude,
+            },
+            distance: None,
+            name: "".to_owned(),
+            sponsor: "".to_owned(),
+            url: "".to_owned(),
+        };
+        // Two clusters, sorted by distance from a client at (34, -118)
+        let servers = vec![
+            server(1, 34.0, -118.0),
+            server(2, 34.1, -118.1),
+            server(3, 34.2, -118.0),
+            server(4, 37.7, -122.4),
+            server(5, 37.8, -122.3),
+            server(6, 40.7, -74.0),
+        ];
+
+        let ids = |servers: Vec<SpeedTestServer>| -> Vec<u32> {
+            servers.iter().map(|server| server.id).collect()
+        };
+        assert_eq!(
+            vec![1, 5, 3],
+            ids(SpeedTestServersConfig::diverse_servers(&servers, 3, 5))
+        );
+        assert_eq!(
+            vec![1, 6, 5],
+            ids(SpeedTestServersConfig::diverse_servers(&servers, 3, 6))
+        );
+        assert_eq!(
+            6,
+            SpeedTestServersConfig::diverse_servers(&servers, 10, 2).len()
+        );
+        assert!(SpeedTestServersConfig::diverse_servers(&[], 3, 5).is_empty());
+    }
+
     #[test]
     fn test_fastest_server() {
         let spt_config = sample_spt_config();
@@ -0,0 +1,904 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+#[cfg(feature = "log")]
+use log::info;
+
+#[cfg(not(feature = "log"))]
+use super::log::info;
+
+use crate::error::SpeedTestError;
+use crate::speedtest::{
+    self, CancellationToken, SpeedMeasurement, SpeedTestEndpoints, SpeedTestLatencyOptions,
+    SpeedTestLatencyTestResult, SpeedTestPhase, SpeedTestProgress, SpeedTestResult,
+    SpeedTestServer, SpeedTestServerAttempt,
+};
+use crate::speedtest_cache::SpeedTestCache;
+use crate::speedtest_config::SpeedTestConfig;
+use crate::speedtest_loaded_latency::LatencyProbe;
+use crate::speedtest_servers_config::{SpeedTestServerQuery, SpeedTestServersConfig};
+
+/// Number of servers that are probed for latency, unless configured otherwise.
+pub const DEFAULT_LATENCY_CANDIDATES: usize = 5;
+
+/// With [`SpeedTestServerSelection::Diverse`], candidates are picked among this many
+/// times as many of the closest servers.
+const DIVERSE_POOL_FACTOR: usize = 10;
+
+/// Number of servers from the latency ranking tried after the selected one fails
+/// during a phase, unless configured otherwise.
+pub const DEFAULT_FALLBACK_SERVERS: usize = 2;
+
+/// How the server to test against is chosen.
+#[derive(Clone, Debug, Default)]
+pub enum SpeedTestServerSelection {
+    /// Probe the servers closest to the client and use the one with the lowest latency.
+    #[default]
+    Closest,
+    /// Probe a geographically diverse sample of the servers close to the client and use
+    /// the one with the lowest latency, for links where the closest servers are all poor.
+    Diverse,
+    /// Always use this server, e.g. a speedtest-mini instance.
+    Fixed(SpeedTestServer),
+    /// Probe the servers with these IDs from the server list and use the one with the
+    /// lowest latency.
+    Ids(Vec<u32>),
+}
+
+/// Builder for a [`SpeedTest`] session.
+#[derive(Debug)]
+pub struct SpeedTestBuilder {
+    endpoints: SpeedTestEndpoints,
+    cache: Option<SpeedTestCache>,
+    config: Option<SpeedTestConfig>,
+    config_file: Option<PathBuf>,
+    servers_file: Option<PathBuf>,
+    download_length: Option<Duration>,
+    upload_length: Option<Duration>,
+    warm_up: Option<Duration>,
+    download_threads: Option<usize>,
+    upload_threads: Option<usize>,
+    server_selection: SpeedTestServerSelection,
+    excluded_servers: Vec<u32>,
+    server_query: SpeedTestServerQuery,
+    latency_candidates: usize,
+    latency_options: SpeedTestLatencyOptions,
+    loaded_latency: bool,
+    fallback_servers: usize,
+    download: bool,
+    upload: bool,
+    share: bool,
+    cancellation_token: CancellationToken,
+}
+
+impl Default for SpeedTestBuilder {
+    fn default() -> Self {
+        SpeedTestBuilder {
+            endpoints: SpeedTestEndpoints::default(),
+            cache: None,
+            config: None,
+            config_file: None,
+            servers_file: None,
+            download_length: None,
+            upload_length: None,
+            warm_up: None,
+            download_threads: None,
+            upload_threads: None,
+            server_selection: SpeedTestServerSelection::default(),
+            excluded_servers: vec![],
+            server_query: SpeedTestServerQuery::default(),
+            latency_candidates: DEFAULT_LATENCY_CANDIDATES,
+            latency_options: SpeedTestLatencyOptions::default(),
+            loaded_latency: false,
+            fallback_servers: DEFAULT_FALLBACK_SERVERS,
+            download: true,
+            upload: true,
+            share: false,
+            cancellation_token: CancellationToken::default(),
+        }
+    }
+}
+
+impl SpeedTestBuilder {
+    pub fn endpoints(mut self, endpoints: SpeedTestEndpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Cache the downloaded configuration and server list here, and use them from here
+    /// while they are fresh. Not used unless set.
+    pub fn cache(mut self, cache: SpeedTestCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Use this configuration instead of downloading it, e.g. [`SpeedTestConfig::default`]
+    /// for a fixed server where speedtest.net is unreachable.
+    pub fn config(mut self, config: SpeedTestConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Read the configuration from this file instead of downloading it, see
+    /// [`speedtest::get_configuration_from_file`].
+    pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// Read the server list from this file instead of downloading it, see
+    /// [`speedtest::get_server_list_from_file`].
+    pub fn servers_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.servers_file = Some(path.into());
+        self
+    }
+
+    /// Override the download test length from the speedtest.net configuration.
+    pub fn download_length(mut self, length: Duration) -> Self {
+        self.download_length = Some(length);
+        self
+    }
+
+    /// Override the upload test length from the speedtest.net configuration.
+    pub fn upload_length(mut self, length: Duration) -> Self {
+        self.upload_length = Some(length);
+        self
+    }
+
+    /// Leave the start of each test out of the steady-state throughput.
+    pub fn warm_up(mut self, warm_up: Duration) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// Override the number of download threads from the speedtest.net configuration.
+    pub fn download_threads(mut self, threads: usize) -> Self {
+        self.download_threads = Some(threads);
+        self
+    }
+
+    /// Override the number of upload threads from the speedtest.net configuration.
+    pub fn upload_threads(mut self, threads: usize) -> Self {
+        self.upload_threads = Some(threads);
+        self
+    }
+
+    pub fn server_selection(mut self, server_selection: SpeedTestServerSelection) -> Self {
+        self.server_selection = server_selection;
+        self
+    }
+
+    /// Never test against the servers with these IDs.
+    pub fn exclude_servers(mut self, ids: Vec<u32>) -> Self {
+        self.excluded_servers = ids;
+        self
+    }
+
+    /// Only consider the servers from the server list matching `query`.
+    pub fn server_query(mut self, query: SpeedTestServerQuery) -> Self {
+        self.server_query = query;
+        self
+    }
+
+    /// Number of servers probed for latency with [`SpeedTestServerSelection::Closest`]
+    /// and [`SpeedTestServerSelection::Diverse`]. Defaults to
+    /// [`DEFAULT_LATENCY_CANDIDATES`].
+    pub fn latency_candidates(mut self, latency_candidates: usize) -> Self {
+        self.latency_candidates = latency_candidates;
+        self
+    }
+
+    /// Number of latency samples taken per candidate server. Defaults to
+    /// [`speedtest::DEFAULT_LATENCY_SAMPLES`].
+    pub fn latency_samples(mut self, latency_samples: usize) -> Self {
+        self.latency_options.samples = latency_samples;
+        self
+    }
+
+    /// Time after which a candidate server is not sampled anymore.
+    pub fn latency_timeout(mut self, timeout: Duration) -> Self {
+        self.latency_options.server_timeout = timeout;
+        self
+    }
+
+    /// Time after which server selection stops sampling altogether.
+    pub fn latency_deadline(mut self, deadline: Duration) -> Self {
+        self.latency_options.deadline = deadline;
+        self
+    }
+
+    /// Whether to sample the latency while the download and upload tests run, to
+    /// detect bufferbloat. Defaults to `false`.
+    pub fn loaded_latency(mut self, loaded_latency: bool) -> Self {
+        self.loaded_latency = loaded_latency;
+        self
+    }
+
+    /// Number of servers from the latency ranking to try, one after the other, when the
+    /// download or upload test fails on the selected server. Defaults to
+    /// [`DEFAULT_FALLBACK_SERVERS`].
+    pub fn fallback_servers(mut self, fallback_servers: usize) -> Self {
+        self.fallback_servers = fallback_servers;
+        self
+    }
+
+    /// Whether [`SpeedTest::run`] runs the download test. Defaults to `true`.
+    pub fn download(mut self, download: bool) -> Self {
+        self.download = download;
+        self
+    }
+
+    /// Whether [`SpeedTest::run`] runs the upload test. Defaults to `true`.
+    pub fn upload(mut self, upload: bool) -> Self {
+        self.upload = upload;
+        self
+    }
+
+    /// Whether [`SpeedTest::run`] shares the result on speedtest.net. Defaults to `false`.
+    pub fn share(mut self, share: bool) -> Self {
+        self.share = share;
+        self
+    }
+
+    /// Token that stops the session's tests early when cancelled.
+    pub fn cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = cancellation_token;
+        self
+    }
+
+    /// Retrieve the configuration, unless one was given, and, unless a fixed server was
+    /// given, the server list, from the configured files or else from the endpoints.
+    pub fn build(mut self) -> Result<SpeedTest, SpeedTestError> {
+        let mut config = match (self.config.take(), &self.config_file) {
+            (Some(config), _) => config,
+            (None, Some(path)) => speedtest::get_configuration_from_file(path)?,
+            (None, None) => speedtest::get_configuration(&self.endpoints, self.cache.as_ref())?,
+        };
+        if let Some(length) = self.download_length {
+            config.length.set_download(length);
+        }
+        if let Some(length) = self.upload_length {
+            config.length.set_upload(length);
+        }
+        if let Some(warm_up) = self.warm_up {
+            config.length.set_warm_up(warm_up);
+        }
+        if let Some(threads) = self.download_threads {
+            config.threads.download = threads;
+        }
+        if let Some(threads) = self.upload_threads {
+            config.threads.upload = threads;
+        }
+
+        let servers = match &self.server_selection {
+            SpeedTestServerSelection::Closest | SpeedTestServerSelection::Diverse => {
+                let mut server_list = self.server_list(&config)?;
+                server_list.exclude_servers(&self.excluded_servers);
+                server_list.filter(&self.server_query);
+                server_list.servers_sorted_by_distance(&config)
+            }
+            SpeedTestServerSelection::Fixed(server) => vec![server.clone()],
+            SpeedTestServerSelection::Ids(ids) => {
+                let mut server_list = self.server_list(&config)?;
+                server_list.exclude_servers(&self.excluded_servers);
+                server_list.filter(&self.server_query);
+                server_list.pin_servers(ids)?;
+                server_list.servers_sorted_by_distance(&config)
+            }
+        };
+
+        Ok(SpeedTest {
+            endpoints: self.endpoints,
+            config,
+            servers,
+            server_selection: self.server_selection,
+            latency_candidates: self.latency_candidates,
+            latency_options: self.latency_options,
+            loaded_latency: self.loaded_latency,
+            fallback_servers: self.fallback_servers,
+            upload_threads: self.upload_threads,
+            download: self.download,
+            upload: self.upload,
+            share: self.share,
+            cancellation_token: self.cancellation_token,
+            started: None,
+            latency_ranking: vec![],
+            latency_measurement: None,
+            download_measurement: None,
+            upload_measurement: None,
+            download_loaded_latency: None,
+            upload_loaded_latency: None,
+            failed_attempts: vec![],
+            fallback_server: None,
+            download_server: None,
+            upload_server: None,
+            share_url: None,
+        })
+    }
+
+    fn server_list(
+        &self,
+        config: &SpeedTestConfig,
+    ) -> Result<SpeedTestServersConfig, SpeedTestError> {
+        match &self.servers_file {
+            Some(path) => speedtest::get_server_list_from_file(path, config),
+            None => {
+                speedtest::get_server_list_with_config(&self.endpoints, self.cache.as_ref(), config)
+            }
+        }
+    }
+}
+
+/// A speed test session owning the configuration and server list.
+///
+/// Use [`SpeedTest::run`] to run every enabled phase at once, or call the phase
+/// methods individually to report on progress in between.
+#[derive(Debug)]
+pub struct SpeedTest {
+    endpoints: SpeedTestEndpoints,
+    config: SpeedTestConfig,
+    servers: Vec<SpeedTestServer>,
+    server_selection: SpeedTestServerSelection,
+    latency_candidates: usize,
+    latency_options: SpeedTestLatencyOptions,
+    loaded_latency: bool,
+    fallback_servers: usize,
+    upload_threads: Option<usize>,
+    download: bool,
+    upload: bool,
+    share: bool,
+    cancellation_token: CancellationToken,
+    started: Option<DateTime<Utc>>,
+    latency_ranking: Vec<SpeedTestLatencyTestResult>,
+    latency_measurement: Option<SpeedTestLatencyTestResult>,
+    download_measurement: Option<SpeedMeasurement>,
+    upload_measurement: Option<SpeedMeasurement>,
+    download_loaded_latency: Option<SpeedTestLatencyTestResult>,
+    upload_loaded_latency: Option<SpeedTestLatencyTestResult>,
+    failed_attempts: Vec<SpeedTestServerAttempt>,
+    /// Server used instead of the selected one after a fallback that happened once a
+    /// phase had completed, so that the earlier phases stay attributed to the selected one.
+    fallback_server: Option<SpeedTestServer>,
+    download_server: Option<SpeedTestServer>,
+    upload_server: Option<SpeedTestServer>,
+    share_url: Option<String>,
+}
+
+impl SpeedTest {
+    pub fn builder() -> SpeedTestBuilder {
+        SpeedTestBuilder::default()
+    }
+
+    pub fn endpoints(&self) -> &SpeedTestEndpoints {
+        &self.endpoints
+    }
+
+    pub fn config(&self) -> &SpeedTestConfig {
+        &self.config
+    }
+
+    /// Servers available to this session, sorted by distance from the client.
+    pub fn servers(&self) -> &[SpeedTestServer] {
+        &self.servers
+    }
+
+    /// Pick the server to test against according to the server selection policy.
+    pub fn select_server(&mut self) -> Result<&SpeedTestLatencyTestResult, SpeedTestError> {
+        self.started.get_or_insert_with(Utc::now);
+        let latency_measurement = self
+            .rank_servers()?
+            .iter()
+            .find(|result| result.error.is_none())
+            .cloned()
+            .ok_or(SpeedTestError::LatencyTestClosestError)?;
+        Ok(self.latency_measurement.insert(latency_measurement))
+    }
+
+    /// Probe the candidate servers for latency and rank them, fewest lost samples and then
+    /// lowest latency first, followed by the servers that could not be reached.
+    pub fn rank_servers(&mut self) -> Result<&[SpeedTestLatencyTestResult], SpeedTestError> {
+        let candidates = match self.server_selection {
+            SpeedTestServerSelection::Closest => {
+                let count = self.latency_candidates.min(self.servers.len());
+                self.servers[..count].to_vec()
+            }
+            SpeedTestServerSelection::Diverse => SpeedTestServersConfig::diverse_servers(
+                &self.servers,
+                self.latency_candidates,
+                self.latency_candidates.saturating_mul(DIVERSE_POOL_FACTOR),
+            ),
+            SpeedTestServerSelection::Fixed(_) | SpeedTestServerSelection::Ids(_) => {
+                self.servers.clone()
+            }
+        };
+        for _server in &candidates {
+            info!("Close Server: {_server:?}");
+        }
+        self.latency_ranking = speedtest::rank_servers_by_latency(
+            &candidates,
+            &self.latency_options,
+            &self.cancellation_token,
+        )?;
+        Ok(&self.latency_ranking)
+    }
+
+    /// Candidate servers probed by [`SpeedTest::rank_servers`], in ranking order.
+    pub fn latency_ranking(&self) -> &[SpeedTestLatencyTestResult] {
+        &self.latency_ranking
+    }
+
+    /// Run the download test against the selected server, selecting one first if needed.
+    ///
+    /// If the test fails, it is repeated on the next servers in the latency ranking.
+    pub fn test_download<F>(
+        &mut self,
+        progress_callback: F,
+    ) -> Result<&SpeedMeasurement, SpeedTestError>
+    where
+        F: Fn(&SpeedTestProgress) + Send + Sync + 'static,
+    {
+        let progress_callback = Arc::new(progress_callback);
+        loop {
+            let server = self.selected_server()?;
+            let probe = self.start_latency_probe(&server)?;
+            let attempt_progress_callback = progress_callback.clone();
+            match speedtest::test_download_with_progress_and_config(
+                &server,
+                move |progress: &SpeedTestProgress| attempt_progress_callback(progress),
+                &mut self.config,
+                &self.cancellation_token,
+            ) {
+                Ok(measurement) => {
+                    self.download_loaded_latency = probe.map(LatencyProbe::stop);
+                    self.download_server = Some(server);
+                    return Ok(self.download_measurement.insert(measurement));
+                }
+                Err(err) => self.fall_back(server, SpeedTestPhase::Download, err)?,
+            }
+        }
+    }
+
+    /// Run the upload test against the selected server, selecting one first if needed.
+    ///
+    /// If the test fails, it is repeated on the next servers in the latency ranking.
+    pub fn test_upload<F>(
+        &mut self,
+        progress_callback: F,
+    ) -> Result<&SpeedMeasurement, SpeedTestError>
+    where
+        F: Fn(&SpeedTestProgress) + Send + Sync + 'static,
+    {
+        // The download test may have raised the upload thread count for fast links,
+        // but an explicit override always wins.
+        if let Some(threads) = self.upload_threads {
+            self.config.threads.upload = threads;
+        }
+        let progress_callback = Arc::new(progress_callback);
+        loop {
+            let server = self.selected_server()?;
+            let probe = self.start_latency_probe(&server)?;
+            let attempt_progress_callback = progress_callback.clone();
+            match speedtest::test_upload_with_progress_and_config(
+                &server,
+                move |progress: &SpeedTestProgress| attempt_progress_callback(progress),
+                &self.config,
+                &self.cancellation_token,
+            ) {
+                Ok(measurement) => {
+                    self.upload_loaded_latency = probe.map(LatencyProbe::stop);
+                    self.upload_server = Some(server);
+                    return Ok(self.upload_measurement.insert(measurement));
+                }
+                Err(err) => self.fall_back(server, SpeedTestPhase::Upload, err)?,
+            }
+        }
+    }
+
+    /// Servers that failed during a phase so far, in the order they were tried.
+    pub fn failed_attempts(&self) -> &[SpeedTestServerAttempt] {
+        &self.failed_attempts
+    }
+
+    /// Collect the measurements taken so far into a result.
+    pub fn result(&self) -> Result<SpeedTestResult, SpeedTestError> {
+        let latency_measurement = self
+            .latency_measurement
+            .clone()
+            .ok_or(SpeedTestError::LatencyTestClosestError)?;
+        Ok(SpeedTestResult {
+            download_measurement: self.download_measurement.clone(),
+            upload_measurement: self.upload_measurement.clone(),
+            server: latency_measurement.server.clone(),
+            latency_measurement,
+            client: self.config.client.clone(),
+            timestamp: self.started.unwrap_or_else(Utc::now),
+            share_url: self.share_url.clone(),
+            download_loaded_latency: self.download_loaded_latency.clone(),
+            upload_loaded_latency: self.upload_loaded_latency.clone(),
+            failed_attempts: self.failed_attempts.clone(),
+            download_server: self.download_server.clone(),
+            upload_server: self.upload_server.clone(),
+        })
+    }
+
+    /// Share the measurements taken so far on speedtest.net and return the result image URL.
+    pub fn share(&mut self) -> Result<&str, SpeedTestError> {
+        let share_url = speedtest::get_share_url(&self.endpoints, &self.result()?)?;
+        Ok(self.share_url.insert(share_url))
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+
+    /// Select a server and run every enabled phase.
+    ///
+    /// If the session is cancelled after a server was selected, the remaining phases
+    /// are skipped and the partial result is returned.
+    pub fn run(mut self) -> Result<SpeedTestResult, SpeedTestError> {
+        self.select_server()?;
+        if self.download && !self.cancellation_token.is_cancelled() {
+            self.test_download(|_| {})?;
+        }
+        if self.upload && !self.cancellation_token.is_cancelled() {
+            self.test_upload(|_| {})?;
+        }
+        if self.share && !self.cancellation_token.is_cancelled() {
+            self.share()?;
+        }
+        self.result()
+    }
+
+    fn start_latency_probe(
+        &self,
+        server: &SpeedTestServer,
+    ) -> Result<Option<LatencyProbe>, SpeedTestError> {
+        if !self.loaded_latency {
+            return Ok(None);
+        }
+        LatencyProbe::start(server).map(Some)
+    }
+
+    /// Record that `server` failed during `phase` and switch to the next reachable server
+    /// from the latency ranking that hasn't failed yet. The selected server is only
+    /// replaced while no phase has completed on it. Gives up with `error` when the test
+    /// was cancelled, the fallbacks are used up or no server is left.
+    fn fall_back(
+        &mut self,
+        server: SpeedTestServer,
+        phase: SpeedTestPhase,
+        error: SpeedTestError,
+    ) -> Result<(), SpeedTestError> {
+        if matches!(error, SpeedTestError::Cancelled) {
+            return Err(error);
+        }
+        info!("{phase:?} failed on {server:?}: {error:?}");
+        let fallbacks = self
+            .failed_attempts
+            .iter()
+            .filter(|attempt| attempt.phase == phase)
+            .count();
+        self.failed_attempts.push(SpeedTestServerAttempt {
+            server,
+            phase,
+            error: error.to_string(),
+        });
+        if fallbacks >= self.fallback_servers {
+            return Err(error);
+        }
+        let next = self
+            .latency_ranking
+            .iter()
+            .filter(|result| result.error.is_none())
+            .find(|result| {
+                !self
+                    .failed_attempts
+                    .iter()
+                    .any(|attempt| attempt.server.url == result.server.url)
+            })
+            .cloned();
+        match next {
+            Some(next) if self.download_server.is_some() || self.upload_server.is_some() => {
+                self.fallback_server = Some(next.server);
+                Ok(())
+            }
+            Some(next) => {
+                self.latency_measurement = Some(next);
+                Ok(())
+            }
+            None => Err(error),
+        }
+    }
+
+    fn selected_server(&mut self) -> Result<SpeedTestServer, SpeedTestError> {
+        if let Some(fallback_server) = &self.fallback_server {
+            return Ok(fallback_server.clone());
+        }
+        if let Some(latency_measurement) = &self.latency_measurement {
+            return Ok(latency_measurement.server.clone());
+        }
+        Ok(self.select_server()?.server.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sample_server, unreachable_url};
+
+    #[test]
+    fn test_build_with_endpoints() {
+        let mut server = mockito::Server::new();
+
+        let _config = server
+            .mock("GET", "/speedtest-config.php")
+            .with_status(200)
+            .with_body_from_file("tests/config/config.php.xml")
+            .create();
+        let _servers = server
+            .mock("GET", "/speedtest-servers.php")
+            .with_status(200)
+            .with_body_from_file("tests/config/geo-test-servers-static.php.xml")
+            .create();
+
+        let session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&server.url()))
+            .upload_length(Duration::from_secs(3))
+            .warm_up(Duration::from_secs(1))
+            .download_threads(2)
+            .build()
+            .unwrap();
+        assert_eq!(Duration::from_secs(3), session.config().length.upload);
+        assert_eq!(Duration::from_secs(1), session.config().length.warm_up);
+        assert_eq!(2, session.config().threads.download);
+        assert_eq!("Los Angeles, CA", session.servers()[0].name);
+    }
+
+    #[test]
+    fn test_build_from_files() {
+        let session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&unreachable_url()))
+            .config_file("tests/config/config.php.xml")
+            .servers_file("tests/config/servers.json")
+            .build()
+            .unwrap();
+        assert_eq!("Cox Communications", session.config().client.isp);
+        assert_eq!(3, session.servers().len());
+        assert_eq!("Los Angeles, CA", session.servers()[0].name);
+    }
+
+    #[test]
+    fn test_build_with_server_ids() {
+        let mut server = mockito::Server::new();
+
+        let _config = server
+            .mock("GET", "/speedtest-config.php")
+            .with_status(200)
+            .with_body_from_file("tests/config/config.php.xml")
+            .create();
+        let _servers = server
+            .mock("GET", "/speedtest-servers.php")
+            .with_status(200)
+            .with_body_from_file("tests/config/geo-test-servers-static.php.xml")
+            .expect(2)
+            .create();
+
+        let session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&server.url()))
+            .server_selection(SpeedTestServerSelection::Ids(vec![4961, 5256]))
+            .exclude_servers(vec![5256])
+            .build();
+        assert!(matches!(session, Err(SpeedTestError::ServerNotFound(5256))));
+
+        let session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&server.url()))
+            .server_selection(SpeedTestServerSelection::Ids(vec![4961, 5256]))
+            .build()
+            .unwrap();
+        assert_eq!(2, session.servers().len());
+    }
+
+    /// Serve the configuration and a server list with a "Broken" and a "Working"
+    /// server from `origin`. The broken server answers latency probes fastest.
+    fn mock_origin(
+        origin: &mut mockito::Server,
+        broken: &mut mockito::Server,
+        working: &mut mockito::Server,
+    ) -> Vec<mockito::Mock> {
+        let servers_xml = format!(
+            r#"<settings><servers>
+<server url="{}/speedtest/upload.php" lat="32.9954" lon="-117.0753" name="Broken" country="United States" cc="US" sponsor="Broken" id="1" host="broken:8080"/>
+<server url="{}/speedtest/upload.php" lat="32.9954" lon="-117.0753" name="Working" country="United States" cc="US" sponsor="Working" id="2" host="working:8080"/>
+</servers></settings>"#,
+            broken.url(),
+            working.url()
+        );
+        vec![
+            origin
+                .mock("GET", "/speedtest-config.php")
+                .with_status(200)
+                .with_body_from_file("tests/config/config.php.xml")
+                .create(),
+            origin
+                .mock("GET", "/speedtest-servers.php")
+                .with_status(200)
+                .with_body(servers_xml)
+                .create(),
+            broken
+                .mock("GET", "/speedtest/latency.txt")
+                .with_status(200)
+                .with_body("test=test")
+                .create(),
+            working
+                .mock("GET", "/speedtest/latency.txt")
+                .with_status(200)
+                .with_chunked_body(|w| {
+                    std::thread::sleep(Duration::from_millis(100));
+                    w.write_all(b"test=test")
+                })
+                .create(),
+            working
+                .mock("GET", mockito::Matcher::Regex(r"\.jpg".to_owned()))
+                .with_status(200)
+                .with_body(vec![0u8; 4096])
+                .create(),
+            working
+                .mock("POST", "/speedtest/upload.php")
+                .with_status(200)
+                .create(),
+        ]
+    }
+
+    #[test]
+    fn test_download_falls_back_to_next_server() {
+        let mut origin = mockito::Server::new();
+        let mut broken = mockito::Server::new();
+        let mut working = mockito::Server::new();
+        let _mocks = mock_origin(&mut origin, &mut broken, &mut working);
+
+        let _broken_download = broken
+            .mock("GET", mockito::Matcher::Regex(r"\.jpg".to_owned()))
+            .with_status(200)
+            .with_chunked_body(|_| Err(std::io::Error::other("connection dropped")))
+            .create();
+
+        let mut session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&origin.url()))
+            .download_length(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        assert_eq!("Broken", session.select_server().unwrap().server.name);
+        assert!(session.test_download(|_| {}).unwrap().size > 0);
+
+        let result = session.result().unwrap();
+        assert_eq!("Working", result.server.name);
+        assert_eq!(1, result.failed_attempts.len());
+        assert_eq!("Broken", result.failed_attempts[0].server.name);
+        assert_eq!(SpeedTestPhase::Download, result.failed_attempts[0].phase);
+        assert_eq!("Working", result.download_server.unwrap().name);
+    }
+
+    #[test]
+    fn test_download_falls_back_on_error_status() {
+        let mut origin = mockito::Server::new();
+        let mut broken = mockito::Server::new();
+        let mut working = mockito::Server::new();
+        let _mocks = mock_origin(&mut origin, &mut broken, &mut working);
+
+        let _broken_download = broken
+            .mock("GET", mockito::Matcher::Regex(r"\.jpg".to_owned()))
+            .with_status(500)
+            .with_body("Internal Server Error")
+            .create();
+
+        let mut session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&origin.url()))
+            .download_length(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        assert_eq!("Broken", session.select_server().unwrap().server.name);
+        assert!(session.test_download(|_| {}).unwrap().size > 0);
+
+        let result = session.result().unwrap();
+        assert_eq!("Working", result.download_server.unwrap().name);
+        assert_eq!(1, result.failed_attempts.len());
+        assert_eq!("Broken", result.failed_attempts[0].server.name);
+        assert_eq!(SpeedTestPhase::Download, result.failed_attempts[0].phase);
+        assert!(result.failed_attempts[0]
+            .error
+            .starts_with("HTTP status server error (500 Internal Server Error)"));
+    }
+
+    #[test]
+    fn test_upload_fallback_keeps_download_server() {
+        let mut origin = mockito::Server::new();
+        let mut broken = mockito::Server::new();
+        let mut working = mockito::Server::new();
+        let _mocks = mock_origin(&mut origin, &mut broken, &mut working);
+
+        // The broken server only rejects uploads.
+        let _broken_download = broken
+            .mock("GET", mockito::Matcher::Regex(r"\.jpg".to_owned()))
+            .with_status(200)
+            .with_body(vec![0u8; 4096])
+            .create();
+        let _broken_upload = broken
+            .mock("POST", "/speedtest/upload.php")
+            .with_status(500)
+            .create();
+
+        // Small uploads, so that the rejections come back before the test ends.
+        let mut config = SpeedTestConfig::default();
+        config.sizes.upload = vec![32768];
+        config.counts.upload = 4;
+        let mut session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&origin.url()))
+            .config(config)
+            .download_length(Duration::from_millis(500))
+            .upload_length(Duration::from_secs(1))
+            .build()
+            .unwrap();
+        assert_eq!("Broken", session.select_server().unwrap().server.name);
+        session.test_download(|_| {}).unwrap();
+        assert!(session.test_upload(|_| {}).unwrap().size > 0);
+
+        let result = session.result().unwrap();
+        assert_eq!("Broken", result.server.name);
+        assert_eq!("Broken", result.latency_measurement.server.name);
+        assert_eq!("Broken", result.download_server.unwrap().name);
+        assert_eq!("Working", result.upload_server.unwrap().name);
+        assert_eq!(1, result.failed_attempts.len());
+        assert_eq!(SpeedTestPhase::Upload, result.failed_attempts[0].phase);
+    }
+
+    #[test]
+    fn test_fixed_server_without_speedtest_net() {
+        let mut mini = mockito::Server::new();
+        let _latency = mini
+            .mock("GET", "/speedtest/latency.txt")
+            .with_status(200)
+            .with_body("test=test")
+            .create();
+
+        let mini_server = SpeedTestServer {
+            host: mini.host_with_port(),
+            id: 0,
+            ..sample_server(&mini.url())
+        };
+        let mut session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&unreachable_url()))
+            .config(SpeedTestConfig::default())
+            .server_selection(SpeedTestServerSelection::Fixed(mini_server))
+            .build()
+            .unwrap();
+        assert_eq!(None, session.config().client.ip);
+        assert_eq!(8, session.config().threads.download);
+        assert!(session.select_server().unwrap().error.is_none());
+    }
+
+    #[test]
+    fn test_result_requires_selected_server() {
+        let mut server = mockito::Server::new();
+
+        let _config = server
+            .mock("GET", "/speedtest-config.php")
+            .with_status(200)
+            .with_body_from_file("tests/config/config.php.xml")
+            .create();
+
+        let fixed_server = SpeedTestServer {
+            id: 0,
+            ..sample_server("")
+        };
+        let session = SpeedTest::builder()
+            .endpoints(SpeedTestEndpoints::with_base_url(&server.url()))
+            .server_selection(SpeedTestServerSelection::Fixed(fixed_server))
+            .build()
+            .unwrap();
+        assert_eq!(1, session.servers().len());
+        assert!(session.result().is_err());
+    }
+}
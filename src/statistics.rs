@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+/// Summary of a series of values, e.g. the throughput of each sample interval.
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct Statistics {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub p10: f64,
+    pub p90: f64,
+    /// Population standard deviation
+    pub stddev: f64,
+}
+
+impl Statistics {
+    /// Summarize `values`, or `None` if there are none.
+    pub fn from_values(values: &[f64]) -> Option<Statistics> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+
+        let mean = sorted.iter().sum::<f64>() / sorted.len() as f64;
+        let variance = sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / sorted.len() as f64;
+        Some(Statistics {
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean,
+            median: percentile(&sorted, 50.0),
+            p10: percentile(&sorted, 10.0),
+            p90: percentile(&sorted, 90.0),
+            stddev: variance.sqrt(),
+        })
+    }
+}
+
+/// The `p`th percentile (0 to 100) of sorted, non-empty `values`, interpolating
+/// linearly between the closest ranks.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0).clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_values() {
+        let values: Vec<f64> = (1..=10).rev().map(f64::from).collect();
+        let statistics = Statistics::from_values(&values).unwrap();
+        assert_eq!(1.0, statistics.min);
+        assert_eq!(10.0, statistics.max);
+        assert_eq!(5.5, statistics.mean);
+        assert_eq!(5.5, statistics.median);
+        assert!((statistics.p10 - 1.9).abs() < 1e-9);
+        assert!((statistics.p90 - 9.1).abs() < 1e-9);
+        assert!((statistics.stddev - 8.25f64.sqrt()).abs() < 1e-9);
+
+        assert_eq!(None, Statistics::from_values(&[]));
+    }
+
+    #[test]
+    fn test_percentile_single_value() {
+        assert_eq!(3.0, percentile(&[3.0], 90.0));
+    }
+}
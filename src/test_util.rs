@@ -0,0 +1,53 @@
+//! Fixtures shared by the unit tests.
+
+use std::time::Duration;
+
+use crate::distance::EarthLocation;
+use crate::speedtest::SpeedTestServer;
+use crate::speedtest_config::{
+    SpeedTestConfig, SpeedTestCountsConfig, SpeedTestSizeConfig, SpeedTestThreadsConfig,
+};
+
+/// A server with ID 1 whose upload URL is on the origin `url`.
+pub(crate) fn sample_server(url: &str) -> SpeedTestServer {
+    SpeedTestServer {
+        country: "".to_owned(),
+        country_code: "".to_owned(),
+        host: "".to_owned(),
+        id: 1,
+        location: EarthLocation::default(),
+        distance: None,
+        name: "".to_owned(),
+        sponsor: "".to_owned(),
+        url: format!("{url}/speedtest/upload.php"),
+    }
+}
+
+/// Small downloads and uploads over a single connection, with enough time to finish.
+pub(crate) fn small_transfer_config() -> SpeedTestConfig {
+    let mut config = SpeedTestConfig {
+        sizes: SpeedTestSizeConfig {
+            upload: vec![32768],
+            download: vec![350],
+        },
+        counts: SpeedTestCountsConfig {
+            upload: 20,
+            download: 1,
+        },
+        threads: SpeedTestThreadsConfig {
+            upload: 1,
+            download: 1,
+        },
+        upload_max: 20,
+        ..SpeedTestConfig::default()
+    };
+    config.length.set_download(Duration::from_secs(30));
+    config.length.set_upload(Duration::from_secs(30));
+    config
+}
+
+/// An origin nothing listens on, so that any request to it fails.
+pub(crate) fn unreachable_url() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}